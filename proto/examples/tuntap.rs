@@ -3,6 +3,8 @@ use proto;
 use proto::device::Device;
 
 use proto::util;
+use proto::util::netlink::Netlink;
+use proto::packet::ipv4::IpAddress;
 use proto::packet::ethernet::Frame;
 
 fn main() {
@@ -12,13 +14,16 @@ fn main() {
     // dev.up().unwrap();
     println!("{:?}", dev);
 
-    util::cmd("ip", vec!["addr", "add", "192.168.100.20/24", "dev", "exp0"]).unwrap();
-    util::cmd("ip", vec!["link", "set", "up", "dev", "exp0"]).unwrap();
+    let nl = Netlink::new().unwrap();
+    nl.add_address("exp0", IpAddress::new(192, 168, 100, 20), 24).unwrap();
+    nl.set_up("exp0").unwrap();
     util::disable_ipv4_forward();
     loop {
         let mut buf = [0u8;256];
         let len = dev.recv(&mut buf).unwrap();
-        let frame = Frame::new(buf.to_vec());
-        frame.log();
+        match Frame::new(&buf[..len]) {
+            Ok(frame) => frame.log(),
+            Err(e) => println!("{}", e),
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use proto::packet::arp::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(p) = Packet::new(data) {
+        let _ = p.len();
+        let _ = p.htype();
+        let _ = p.ptype();
+        let _ = p.operation();
+        let _ = p.source_hardware_addr();
+        let _ = p.source_protocol_addr();
+        let _ = p.target_hardware_addr();
+        let _ = p.target_protocol_addr();
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use proto::packet::ethernet::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frame) = Frame::new(data) {
+        let _ = frame.dst();
+        let _ = frame.src();
+        let _ = frame.ethertype();
+        let _ = frame.payload();
+    }
+});
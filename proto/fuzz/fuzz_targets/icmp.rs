@@ -0,0 +1,20 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use proto::packet::icmp::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(p) = Packet::new(data) {
+        let _ = p.header();
+        let _ = p.typ();
+        let _ = p.code();
+        let _ = p.checksum();
+        let _ = p.check_checksum();
+        let _ = p.data();
+        let _ = p.echo_ident();
+        let _ = p.echo_seqno();
+        let _ = p.echo_data();
+        let _ = p.unreachable_code();
+        let _ = p.unreachable_nexthop();
+        let _ = p.unreachable_data();
+    }
+});
@@ -0,0 +1,24 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use proto::packet::ipv4::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(p) = Packet::new(data) {
+        let _ = p.version();
+        let _ = p.header_length();
+        let _ = p.tos();
+        let _ = p.length();
+        let _ = p.identification();
+        let _ = p.flag();
+        let _ = p.fragment();
+        let _ = p.ttl();
+        let _ = p.protocol();
+        let _ = p.verify_checksum();
+        let _ = p.check_checksum();
+        let _ = p.source_addr();
+        let _ = p.destination_addr();
+        let _ = p.option();
+        let _ = p.header();
+        let _ = p.payload();
+    }
+});
@@ -0,0 +1,131 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use async_trait::async_trait;
+use tokio::io::unix::AsyncFd;
+use crate::device::Device;
+use crate::device::raw_socket::RawSocketDevice;
+use crate::device::tuntap::TapDevice;
+
+#[async_trait]
+pub trait AsyncDevice {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+    async fn send(&self, buf: &[u8]) -> io::Result<usize>;
+}
+
+// drives a blocking device from the tokio reactor, must be created inside a runtime
+#[derive(Debug)]
+pub struct Async<D: Device + AsRawFd> {
+    inner: AsyncFd<D>,
+    // file status flags before O_NONBLOCK was added, restored by into_inner
+    flags: libc::c_int,
+}
+
+pub type AsyncTapDevice = Async<TapDevice>;
+pub type AsyncRawSocketDevice = Async<RawSocketDevice>;
+
+impl<D: Device + AsRawFd> Async<D> {
+    pub fn new(dev: D) -> io::Result<Async<D>> {
+        let flags = set_nonblocking(dev.as_raw_fd())?;
+        Ok(Async {
+            inner: AsyncFd::new(dev)?,
+            flags,
+        })
+    }
+
+    pub fn get_ref(&self) -> &D {
+        self.inner.get_ref()
+    }
+
+    // the device is handed back in the blocking mode it was created with
+    pub fn into_inner(self) -> D {
+        let dev = self.inner.into_inner();
+        let _ = set_flags(dev.as_raw_fd(), self.flags);
+        dev
+    }
+}
+
+impl<D: Device + AsRawFd> AsRawFd for Async<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+#[async_trait]
+impl<D: Device + AsRawFd + Send + Sync> AsyncDevice for Async<D> {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|inner| inner.get_ref().recv(buf)) {
+                Ok(res) => return res,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.inner.writable().await?;
+            match guard.try_io(|inner| inner.get_ref().send(buf)) {
+                Ok(res) => return res,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+// returns the flags from before the change
+fn set_nonblocking(fd: RawFd) -> io::Result<libc::c_int> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 { return Err(io::Error::last_os_error()) }
+    set_flags(fd, flags | libc::O_NONBLOCK)?;
+    Ok(flags)
+}
+
+fn set_flags(fd: RawFd, flags: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Builder;
+    use crate::device::udp_tunnel::UdpTunnelDevice;
+    use std::time::Duration;
+    use crate::util::netns::{readable, Namespace};
+
+    #[test]
+    fn test_async_raw_socket() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.raw_socket("lo").unwrap();
+        let rt = Builder::new_current_thread().enable_io().build().unwrap();
+        rt.block_on(async {
+            let dev = AsyncRawSocketDevice::new(dev).unwrap();
+            let mut frame = [0u8; 60];
+            frame[12..14].copy_from_slice(&[0x88, 0xb5]);
+            frame[14..18].copy_from_slice(&[0xca, 0xfe, 0xba, 0xbe]);
+            assert_eq!(dev.send(&frame).await.unwrap(), frame.len());
+            let mut buf = [0u8; 1500];
+            loop {
+                readable(&dev, Duration::from_secs(5)).unwrap();
+                let len = dev.recv(&mut buf).await.unwrap();
+                if buf[12..18] == frame[12..18] {
+                    assert_eq!(len, frame.len());
+                    break;
+                }
+            }
+        });
+    }
+    #[test]
+    fn test_into_inner_blocking() {
+        let rt = Builder::new_current_thread().enable_io().build().unwrap();
+        let dev = UdpTunnelDevice::bind("127.0.0.1:0").unwrap();
+        let fd = dev.as_raw_fd();
+        let dev = rt.block_on(async { Async::new(dev) }).unwrap();
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFL) } & libc::O_NONBLOCK, 0);
+        let _dev = dev.into_inner();
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFL) } & libc::O_NONBLOCK, 0);
+    }
+}
@@ -0,0 +1,309 @@
+use std::io;
+use crate::packet::ip_protocol::IpProtocol;
+
+// instruction classes
+pub const BPF_LD: u16 = 0x00;
+pub const BPF_LDX: u16 = 0x01;
+pub const BPF_ALU: u16 = 0x04;
+pub const BPF_JMP: u16 = 0x05;
+pub const BPF_RET: u16 = 0x06;
+
+// ld/ldx fields
+pub const BPF_W: u16 = 0x00;
+pub const BPF_H: u16 = 0x08;
+pub const BPF_B: u16 = 0x10;
+pub const BPF_IMM: u16 = 0x00;
+pub const BPF_ABS: u16 = 0x20;
+pub const BPF_IND: u16 = 0x40;
+pub const BPF_LEN: u16 = 0x80;
+pub const BPF_MSH: u16 = 0xa0;
+
+// alu/jmp fields
+pub const BPF_AND: u16 = 0x50;
+pub const BPF_JA: u16 = 0x00;
+pub const BPF_JEQ: u16 = 0x10;
+pub const BPF_JGT: u16 = 0x20;
+pub const BPF_JGE: u16 = 0x30;
+pub const BPF_JSET: u16 = 0x40;
+pub const BPF_K: u16 = 0x00;
+pub const BPF_A: u16 = 0x10;
+
+// kernel limit on the number of instructions
+pub const BPF_MAXINSNS: usize = 4096;
+
+const ETH_P_IP: u32 = 0x0800;
+const ETH_P_ARP: u32 = 0x0806;
+
+// struct sock_filter
+#[repr(C)]
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Instruction {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl Instruction {
+    pub fn stmt(code: u16, k: u32) -> Instruction {
+        Instruction { code, jt: 0, jf: 0, k }
+    }
+
+    pub fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Instruction {
+        Instruction { code, jt, jf, k }
+    }
+
+    fn is_jump(&self) -> bool {
+        self.code & 0x07 == BPF_JMP
+    }
+}
+
+// struct sock_fprog
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct sock_fprog {
+    pub len: libc::c_ushort,
+    pub filter: *const Instruction,
+}
+
+// a validated classic BPF program, attach it with RawSocketDevice::attach_filter
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Program {
+    insns: Vec<Instruction>,
+}
+
+impl Program {
+    // the program must end in a return and every jump must land inside it
+    pub fn new(insns: Vec<Instruction>) -> io::Result<Program> {
+        if insns.is_empty() || insns.len() > BPF_MAXINSNS {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        if insns[insns.len() - 1].code & 0x07 != BPF_RET {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        for (pc, insn) in insns.iter().enumerate() {
+            if !insn.is_jump() {
+                continue;
+            }
+            let rest = insns.len() - pc - 1;
+            let far = if insn.code == BPF_JMP | BPF_JA {
+                insn.k as usize
+            } else {
+                insn.jt.max(insn.jf) as usize
+            };
+            if far >= rest {
+                return Err(io::ErrorKind::InvalidInput.into());
+            }
+        }
+        Ok(Program { insns })
+    }
+
+    // accept frames with the given EtherType
+    pub fn ethertype(typ: u16) -> Program {
+        Builder::new()
+            .ld_h(12)
+            .jeq(typ as u32, "accept", "drop")
+            .label("accept").accept()
+            .label("drop").drop()
+            .build()
+            .unwrap()
+    }
+
+    pub fn arp() -> Program {
+        Program::ethertype(ETH_P_ARP as u16)
+    }
+
+    // accept IPv4 frames carrying the given protocol
+    pub fn ipv4_protocol(proto: IpProtocol) -> Program {
+        Builder::new()
+            .ld_h(12)
+            .jeq(ETH_P_IP, "ipv4", "drop")
+            .label("ipv4")
+            .ld_b(23)
+            .jeq(u8::from(proto) as u32, "accept", "drop")
+            .label("accept").accept()
+            .label("drop").drop()
+            .build()
+            .unwrap()
+    }
+
+    pub fn icmp() -> Program {
+        Program::ipv4_protocol(IpProtocol::ICMP)
+    }
+
+    pub fn accept_all() -> Program {
+        Builder::new().accept().build().unwrap()
+    }
+
+    pub fn drop_all() -> Program {
+        Builder::new().drop().build().unwrap()
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.insns
+    }
+
+    pub fn len(&self) -> usize {
+        self.insns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.insns.is_empty()
+    }
+
+    pub(crate) fn as_fprog(&self) -> sock_fprog {
+        sock_fprog {
+            len: self.insns.len() as libc::c_ushort,
+            filter: self.insns.as_ptr(),
+        }
+    }
+}
+
+// assembler resolving conditional jumps to named labels, labels may only point forward
+#[derive(Debug, Default)]
+pub struct Builder {
+    insns: Vec<(Instruction, Option<(&'static str, &'static str)>)>,
+    labels: Vec<(&'static str, usize)>,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    pub fn push(mut self, insn: Instruction) -> Builder {
+        self.insns.push((insn, None));
+        self
+    }
+
+    // the next instruction is the target of `name`
+    pub fn label(mut self, name: &'static str) -> Builder {
+        self.labels.push((name, self.insns.len()));
+        self
+    }
+
+    pub fn ld_w(self, offset: u32) -> Builder {
+        self.push(Instruction::stmt(BPF_LD | BPF_W | BPF_ABS, offset))
+    }
+
+    pub fn ld_h(self, offset: u32) -> Builder {
+        self.push(Instruction::stmt(BPF_LD | BPF_H | BPF_ABS, offset))
+    }
+
+    pub fn ld_b(self, offset: u32) -> Builder {
+        self.push(Instruction::stmt(BPF_LD | BPF_B | BPF_ABS, offset))
+    }
+
+    pub fn ld_len(self) -> Builder {
+        self.push(Instruction::stmt(BPF_LD | BPF_W | BPF_LEN, 0))
+    }
+
+    // X = 4 * (P[offset] & 0xf), the IPv4 header length
+    pub fn ldx_msh(self, offset: u32) -> Builder {
+        self.push(Instruction::stmt(BPF_LDX | BPF_B | BPF_MSH, offset))
+    }
+
+    pub fn ld_ind_h(self, offset: u32) -> Builder {
+        self.push(Instruction::stmt(BPF_LD | BPF_H | BPF_IND, offset))
+    }
+
+    pub fn and(self, k: u32) -> Builder {
+        self.push(Instruction::stmt(BPF_ALU | BPF_AND | BPF_K, k))
+    }
+
+    pub fn jeq(self, k: u32, jt: &'static str, jf: &'static str) -> Builder {
+        self.jump(BPF_JMP | BPF_JEQ | BPF_K, k, jt, jf)
+    }
+
+    pub fn jgt(self, k: u32, jt: &'static str, jf: &'static str) -> Builder {
+        self.jump(BPF_JMP | BPF_JGT | BPF_K, k, jt, jf)
+    }
+
+    pub fn jge(self, k: u32, jt: &'static str, jf: &'static str) -> Builder {
+        self.jump(BPF_JMP | BPF_JGE | BPF_K, k, jt, jf)
+    }
+
+    pub fn jset(self, k: u32, jt: &'static str, jf: &'static str) -> Builder {
+        self.jump(BPF_JMP | BPF_JSET | BPF_K, k, jt, jf)
+    }
+
+    // return the number of bytes of the frame to keep
+    pub fn ret(self, k: u32) -> Builder {
+        self.push(Instruction::stmt(BPF_RET | BPF_K, k))
+    }
+
+    pub fn accept(self) -> Builder {
+        self.ret(u32::MAX)
+    }
+
+    pub fn drop(self) -> Builder {
+        self.ret(0)
+    }
+
+    fn jump(mut self, code: u16, k: u32, jt: &'static str, jf: &'static str) -> Builder {
+        self.insns.push((Instruction::jump(code, k, 0, 0), Some((jt, jf))));
+        self
+    }
+
+    fn resolve(&self, pc: usize, name: &str) -> io::Result<u8> {
+        let (_, to) = self.labels.iter().find(|(n, _)| *n == name)
+            .ok_or(io::ErrorKind::InvalidInput)?;
+        if *to <= pc || to - pc - 1 > u8::MAX as usize {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        Ok((to - pc - 1) as u8)
+    }
+
+    pub fn build(self) -> io::Result<Program> {
+        let mut insns = Vec::with_capacity(self.insns.len());
+        for (pc, (insn, targets)) in self.insns.iter().enumerate() {
+            let mut insn = *insn;
+            if let Some((jt, jf)) = targets {
+                insn.jt = self.resolve(pc, jt)?;
+                insn.jf = self.resolve(pc, jf)?;
+            }
+            insns.push(insn);
+        }
+        Program::new(insns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arp_program() {
+        // tcpdump -dd arp
+        let expected = [
+            Instruction::stmt(0x28, 12),
+            Instruction::jump(0x15, 0x0806, 0, 1),
+            Instruction::stmt(0x06, 0xffff_ffff),
+            Instruction::stmt(0x06, 0),
+        ];
+        assert_eq!(Program::arp().instructions(), &expected);
+    }
+    #[test]
+    fn test_icmp_program() {
+        let expected = [
+            Instruction::stmt(0x28, 12),
+            Instruction::jump(0x15, 0x0800, 0, 3),
+            Instruction::stmt(0x30, 23),
+            Instruction::jump(0x15, 1, 0, 1),
+            Instruction::stmt(0x06, 0xffff_ffff),
+            Instruction::stmt(0x06, 0),
+        ];
+        assert_eq!(Program::icmp().instructions(), &expected);
+    }
+    #[test]
+    fn test_invalid_program() {
+        assert!(Program::new(vec![]).is_err());
+        assert!(Program::new(vec![Instruction::stmt(BPF_LD | BPF_H | BPF_ABS, 12)]).is_err());
+        assert!(Program::new(vec![
+            Instruction::jump(BPF_JMP | BPF_JEQ | BPF_K, 0, 0, 1),
+            Instruction::stmt(BPF_RET | BPF_K, 0),
+        ]).is_err());
+        assert!(Builder::new().jeq(0, "missing", "missing").drop().build().is_err());
+        assert!(Builder::new().label("back").accept().jeq(0, "back", "back").drop().build().is_err());
+    }
+}
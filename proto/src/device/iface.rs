@@ -0,0 +1,235 @@
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use nix::unistd::close;
+use crate::packet::ethernet::MACAddress;
+
+pub use libc::IFNAMSIZ;
+
+pub const IFF_UP: i16 = 0x1;
+pub const IFF_BROADCAST: i16 = 0x2;
+pub const IFF_LOOPBACK: i16 = 0x8;
+pub const IFF_POINTOPOINT: i16 = 0x10;
+pub const IFF_RUNNING: i16 = 0x40;
+pub const IFF_NOARP: i16 = 0x80;
+pub const IFF_PROMISC: i16 = 0x100;
+pub const IFF_ALLMULTI: i16 = 0x200;
+pub const IFF_MULTICAST: i16 = 0x1000;
+
+pub const ARPHRD_ETHER: u16 = 1;
+
+// the request part of struct ifreq, 24 bytes on 64 bit linux because of ifru_map
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union ifreq_data {
+    pub ifru_flags: libc::c_short,
+    pub ifru_ivalue: libc::c_int,
+    pub ifru_mtu: libc::c_int,
+    pub ifru_hwaddr: libc::sockaddr,
+    ifru_pad: [u8; 24],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ifreq {
+    pub ifr_name: [libc::c_char; IFNAMSIZ],
+    pub ifr_ifru: ifreq_data,
+}
+
+impl ifreq {
+    // an empty name lets TUNSETIFF pick one
+    pub fn new(name: &str) -> io::Result<ifreq> {
+        if name.len() > IFNAMSIZ - 1 || name.as_bytes().contains(&0) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut req = ifreq {
+            ifr_name: [0; IFNAMSIZ],
+            ifr_ifru: ifreq_data { ifru_pad: [0; 24] },
+        };
+        for (i, byte) in name.as_bytes().iter().enumerate() {
+            req.ifr_name[i] = *byte as libc::c_char;
+        }
+        Ok(req)
+    }
+
+    pub fn with_flags(name: &str, flags: i16) -> io::Result<ifreq> {
+        let mut req = ifreq::new(name)?;
+        req.ifr_ifru.ifru_flags = flags;
+        Ok(req)
+    }
+
+    pub fn name(&self) -> String {
+        self.ifr_name.iter().map(|&c| c as u8)
+            .take_while(|&c| c != 0)
+            .map(|c| c as char)
+            .collect::<String>()
+    }
+
+    pub fn flags(&self) -> i16 {
+        unsafe { self.ifr_ifru.ifru_flags }
+    }
+}
+
+impl fmt::Debug for ifreq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ifreq").field("ifr_name", &self.name()).finish()
+    }
+}
+
+// ioctl based access to the attributes of a network interface
+#[derive(Debug)]
+pub struct Interface {
+    fd: RawFd,
+    name: String,
+}
+
+impl AsRawFd for Interface {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Interface {
+    // the interface does not have to exist until it is queried
+    pub fn new(name: &str) -> io::Result<Interface> {
+        if name.is_empty() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        ifreq::new(name)?;
+        let fd = unsafe {
+            let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+            if fd == -1 { return Err(io::Error::last_os_error()) }
+            fd
+        };
+        Ok(Interface {
+            fd,
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn index(&self) -> io::Result<i32> {
+        let req = self.ioctl(libc::SIOCGIFINDEX, ifreq::new(&self.name)?)?;
+        Ok(unsafe { req.ifr_ifru.ifru_ivalue })
+    }
+
+    pub fn mac(&self) -> io::Result<MACAddress> {
+        let req = self.ioctl(libc::SIOCGIFHWADDR, ifreq::new(&self.name)?)?;
+        let addr = unsafe { req.ifr_ifru.ifru_hwaddr };
+        let mut mac = [0u8; 6];
+        for (b, &c) in mac.iter_mut().zip(addr.sa_data.iter()) {
+            *b = c as u8;
+        }
+        Ok(MACAddress::new(mac))
+    }
+
+    // most drivers want the interface down before the address changes
+    pub fn set_mac(&self, mac: MACAddress) -> io::Result<()> {
+        let mut req = ifreq::new(&self.name)?;
+        let mut addr: libc::sockaddr = unsafe { std::mem::zeroed() };
+        addr.sa_family = ARPHRD_ETHER;
+        for (c, &b) in addr.sa_data.iter_mut().zip(mac.as_bytes().iter()) {
+            *c = b as libc::c_char;
+        }
+        req.ifr_ifru.ifru_hwaddr = addr;
+        self.ioctl(libc::SIOCSIFHWADDR, req).map(|_| ())
+    }
+
+    pub fn mtu(&self) -> io::Result<usize> {
+        let req = self.ioctl(libc::SIOCGIFMTU, ifreq::new(&self.name)?)?;
+        Ok(unsafe { req.ifr_ifru.ifru_mtu } as usize)
+    }
+
+    pub fn set_mtu(&self, mtu: usize) -> io::Result<()> {
+        if mtu > libc::c_int::MAX as usize {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut req = ifreq::new(&self.name)?;
+        req.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+        self.ioctl(libc::SIOCSIFMTU, req).map(|_| ())
+    }
+
+    pub fn flags(&self) -> io::Result<i16> {
+        Ok(self.ioctl(libc::SIOCGIFFLAGS, ifreq::new(&self.name)?)?.flags())
+    }
+
+    pub fn set_flags(&self, flags: i16) -> io::Result<()> {
+        self.ioctl(libc::SIOCSIFFLAGS, ifreq::with_flags(&self.name, flags)?).map(|_| ())
+    }
+
+    // set the bits in `set` and clear those in `clear`, other flags are kept
+    pub fn modify_flags(&self, set: i16, clear: i16) -> io::Result<()> {
+        let flags = self.flags()?;
+        self.set_flags((flags | set) & !clear)
+    }
+
+    pub fn up(&self) -> io::Result<()> {
+        self.modify_flags(IFF_UP, 0)
+    }
+
+    pub fn down(&self) -> io::Result<()> {
+        self.modify_flags(0, IFF_UP)
+    }
+
+    pub fn is_up(&self) -> io::Result<bool> {
+        Ok(self.flags()? & IFF_UP != 0)
+    }
+
+    fn ioctl(&self, cmd: libc::c_ulong, mut req: ifreq) -> io::Result<ifreq> {
+        let res = unsafe { libc::ioctl(self.fd, cmd as _, &mut req as *mut ifreq) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(req)
+    }
+}
+
+// index in the calling thread's namespace, unlike Interface::index which asks its own socket
+pub fn interface_index(name: &str) -> io::Result<libc::c_int> {
+    ifreq::new(name)?;
+    let cname = CString::new(name)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let index = unsafe { libc::if_nametoindex(cname.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(index as libc::c_int)
+}
+
+impl Drop for Interface {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ifreq_size() {
+        assert_eq!(std::mem::size_of::<ifreq>(), 40);
+        assert_eq!(ifreq::new("lo").unwrap().name(), "lo");
+        assert!(ifreq::new("rusproto-too-long").is_err());
+    }
+    #[test]
+    fn test_loopback() {
+        let lo = Interface::new("lo").unwrap();
+        assert_eq!(lo.index().unwrap(), 1);
+        assert_eq!(lo.mac().unwrap(), MACAddress::default());
+        let mtu = std::fs::read_to_string("/sys/class/net/lo/mtu").unwrap();
+        assert_eq!(lo.mtu().unwrap(), mtu.trim().parse::<usize>().unwrap());
+        let flags = lo.flags().unwrap();
+        assert_ne!(flags & IFF_LOOPBACK, 0);
+        assert!(lo.is_up().unwrap());
+    }
+    #[test]
+    fn test_unknown_interface() {
+        let dev = Interface::new("rusproto-none").unwrap();
+        assert_eq!(dev.index().unwrap_err().raw_os_error(), Some(libc::ENODEV));
+    }
+}
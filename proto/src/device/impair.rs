@@ -0,0 +1,416 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use crate::device::Device;
+
+// what happens to frames in one direction, probabilities are in 0.0..=1.0
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Impairment {
+    pub loss: f64,
+    pub delay: Duration,
+    // extra delay drawn uniformly from 0..=jitter, may reorder frames
+    pub jitter: Duration,
+    pub duplicate: f64,
+    // hold a frame back until the next one has passed it
+    pub reorder: f64,
+    // flip a single random bit
+    pub corrupt: f64,
+}
+
+impl Impairment {
+    pub fn is_none(&self) -> bool {
+        *self == Impairment::default()
+    }
+}
+
+type Pending = Reverse<(Instant, u64, Vec<u8>)>;
+
+// a held back frame goes out on its own once no successor passed it for this long
+const REORDER_HOLD: Duration = Duration::from_millis(100);
+
+// frames of one direction waiting for their delivery time
+#[derive(Debug, Default)]
+struct Line {
+    config: Impairment,
+    queue: BinaryHeap<Pending>,
+    held: Option<(Instant, Vec<u8>)>,
+    seq: u64,
+}
+
+impl Line {
+    fn new(config: Impairment) -> Line {
+        Line { config, ..Default::default() }
+    }
+
+    fn admit(&mut self, rng: &mut ChaCha8Rng, frame: &[u8], now: Instant) {
+        let c = self.config;
+        if chance(rng, c.loss) {
+            return;
+        }
+        let mut frame = frame.to_vec();
+        if !frame.is_empty() && chance(rng, c.corrupt) {
+            let bit = rng.gen_range(0..frame.len() * 8);
+            frame[bit / 8] ^= 1 << (bit % 8);
+        }
+        let copies = if chance(rng, c.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut due = now + c.delay;
+            if c.jitter > Duration::from_secs(0) {
+                due += rng.gen_range(Duration::from_secs(0)..=c.jitter);
+            }
+            if self.held.is_none() && chance(rng, c.reorder) {
+                self.held = Some((due, frame.clone()));
+                continue;
+            }
+            self.push(due, frame.clone());
+            if let Some((held_due, held)) = self.held.take() {
+                self.push(held_due.max(due), held);
+            }
+        }
+    }
+
+    fn push(&mut self, due: Instant, frame: Vec<u8>) {
+        self.queue.push(Reverse((due, self.seq, frame)));
+        self.seq += 1;
+    }
+
+    // the first frame due at now, it stays queued until pop
+    fn due(&mut self, now: Instant) -> Option<&[u8]> {
+        if matches!(self.held, Some((due, _)) if due + REORDER_HOLD <= now) {
+            self.release();
+        }
+        match self.queue.peek() {
+            Some(Reverse((due, _, frame))) if *due <= now => Some(frame),
+            _ => None,
+        }
+    }
+
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        self.queue.pop().map(|Reverse((_, _, frame))| frame)
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        self.due(now)?;
+        self.pop()
+    }
+
+    // when the head of the queue is due, the held frame does not count
+    fn queue_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((due, _, _))| *due)
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        let held = self.held.as_ref().map(|(due, _)| *due + REORDER_HOLD);
+        match (self.queue_due(), held) {
+            (Some(queued), Some(held)) => Some(queued.min(held)),
+            (queued, held) => queued.or(held),
+        }
+    }
+
+    // a held frame is released without waiting for a successor
+    fn release(&mut self) {
+        if let Some((due, frame)) = self.held.take() {
+            self.push(due, frame);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len() + self.held.iter().count()
+    }
+}
+
+fn chance(rng: &mut ChaCha8Rng, p: f64) -> bool {
+    p > 0.0 && rng.gen_bool(p.min(1.0))
+}
+
+#[derive(Debug)]
+struct State {
+    rng: ChaCha8Rng,
+    tx: Line,
+    rx: Line,
+}
+
+// applies an Impairment to the frames sent to and received from the wrapped device.
+// a fixed seed gives the same decisions for the same sequence of frames
+#[derive(Debug)]
+pub struct Impaired<D: Device> {
+    inner: D,
+    state: Mutex<State>,
+}
+
+impl<D: Device> Impaired<D> {
+    pub fn new(dev: D, seed: u64) -> Impaired<D> {
+        Impaired {
+            inner: dev,
+            state: Mutex::new(State {
+                rng: ChaCha8Rng::seed_from_u64(seed),
+                tx: Line::default(),
+                rx: Line::default(),
+            }),
+        }
+    }
+
+    pub fn with_send(self, config: Impairment) -> Impaired<D> {
+        self.state.lock().unwrap().tx = Line::new(config);
+        self
+    }
+
+    pub fn with_recv(self, config: Impairment) -> Impaired<D> {
+        self.state.lock().unwrap().rx = Line::new(config);
+        self
+    }
+
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    // frames accepted by send but not yet handed to the device
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().tx.len()
+    }
+
+    // when pump has the next delayed frame to send
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.state.lock().unwrap().tx.next_due()
+    }
+
+    // when a nonblocking recv has the next delayed frame to return
+    pub fn next_recv_deadline(&self) -> Option<Instant> {
+        self.state.lock().unwrap().rx.next_due()
+    }
+
+    // send the delayed frames that are due, returns how many were sent
+    pub fn pump(&self) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        self.pump_locked(&mut state)
+    }
+
+    // wait for and send every pending frame, including a held back one
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.tx.release();
+        while let Some(due) = state.tx.next_due() {
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+            self.pump_locked(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn pump_locked(&self, state: &mut State) -> io::Result<usize> {
+        let now = Instant::now();
+        let mut sent = 0;
+        // a frame the device refused stays queued for the next call
+        while let Some(frame) = state.tx.due(now) {
+            self.inner.send(frame)?;
+            state.tx.pop();
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+impl<D: Device + AsRawFd> AsRawFd for Impaired<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<D: Device> Device for Impaired<D> {
+    fn is_nonblocking(&self) -> bool {
+        self.inner.is_nonblocking()
+    }
+
+    // delayed frames are returned once due. a nonblocking device gives WouldBlock until
+    // then, see next_recv_deadline, a blocking one sleeps for them if nothing else is ready.
+    // nothing can wake a blocking recv for a held back frame, there only its successor
+    // releases it
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let nonblocking = self.inner.is_nonblocking();
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                if let Some(frame) = state.rx.pop_due(Instant::now()) {
+                    let len = frame.len().min(buf.len());
+                    buf[..len].copy_from_slice(&frame[..len]);
+                    return Ok(len);
+                }
+                if nonblocking {
+                    None
+                } else {
+                    state.rx.queue_due()
+                }
+            };
+            if let Some(due) = wait {
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+                continue;
+            }
+            let len = self.inner.recv(buf)?;
+            let mut state = self.state.lock().unwrap();
+            let State { rng, rx, .. } = &mut *state;
+            rx.admit(rng, &buf[..len], Instant::now());
+        }
+    }
+
+    // lost frames still count as sent, delayed ones go out from later calls or pump.
+    // a frame refused earlier fails the call before this one is taken in, so a retry
+    // does not duplicate it. once taken in a frame is sent, the error of a refused one
+    // comes from the next call
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        self.pump_locked(&mut state)?;
+        {
+            let State { rng, tx, .. } = &mut *state;
+            tx.admit(rng, buf, Instant::now());
+        }
+        let _ = self.pump_locked(&mut state);
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::pipe::PipeDevice;
+
+    fn drain(dev: &PipeDevice) -> Vec<Vec<u8>> {
+        dev.set_nonblocking(true);
+        let mut frames = Vec::new();
+        let mut buf = [0u8; 64];
+        while let Ok(len) = dev.recv(&mut buf) {
+            frames.push(buf[..len].to_vec());
+        }
+        frames
+    }
+
+    fn send_numbered(dev: &impl Device, n: u8) {
+        for i in 0..n {
+            dev.send(&[i; 8]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_loss_is_deterministic() {
+        let config = Impairment { loss: 0.5, ..Impairment::default() };
+        let run = |seed| {
+            let (a, b) = PipeDevice::pair();
+            let a = Impaired::new(a, seed).with_send(config);
+            send_numbered(&a, 100);
+            drain(&b)
+        };
+        let first = run(7);
+        assert!(first.len() > 20 && first.len() < 80);
+        assert_eq!(first, run(7));
+        assert_ne!(first, run(8));
+    }
+    #[test]
+    fn test_duplicate_and_reorder() {
+        let (a, b) = PipeDevice::pair();
+        let a = Impaired::new(a, 1).with_send(Impairment { duplicate: 1.0, ..Impairment::default() });
+        send_numbered(&a, 2);
+        assert_eq!(drain(&b), vec![vec![0; 8], vec![0; 8], vec![1; 8], vec![1; 8]]);
+
+        let (a, b) = PipeDevice::pair();
+        let a = Impaired::new(a, 1).with_send(Impairment { reorder: 1.0, ..Impairment::default() });
+        send_numbered(&a, 5);
+        assert_eq!(a.pending(), 1);
+        a.flush().unwrap();
+        let order: Vec<u8> = drain(&b).iter().map(|f| f[0]).collect();
+        assert_eq!(order, vec![1, 0, 3, 2, 4]);
+    }
+    #[test]
+    fn test_corrupt() {
+        let (a, b) = PipeDevice::pair();
+        let b = Impaired::new(b, 3).with_recv(Impairment { corrupt: 1.0, ..Impairment::default() });
+        a.send(&[0u8; 32]).unwrap();
+        let mut buf = [0u8; 64];
+        let len = b.recv(&mut buf).unwrap();
+        assert_eq!(len, 32);
+        assert_eq!(buf[..len].iter().map(|b| b.count_ones()).sum::<u32>(), 1);
+    }
+    #[test]
+    fn test_delay() {
+        let delay = Duration::from_millis(30);
+        let (a, b) = PipeDevice::pair();
+        let a = Impaired::new(a, 0).with_send(Impairment { delay, ..Impairment::default() });
+        let start = Instant::now();
+        a.send(&[1]).unwrap();
+        assert!(drain(&b).is_empty());
+        assert!(a.next_deadline().unwrap() >= start + delay);
+        a.flush().unwrap();
+        assert!(start.elapsed() >= delay);
+        assert_eq!(drain(&b), vec![vec![1]]);
+
+        let (a, b) = PipeDevice::pair();
+        let b = Impaired::new(b, 0).with_recv(Impairment { delay, ..Impairment::default() });
+        let start = Instant::now();
+        a.send(&[2]).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(b.recv(&mut buf).unwrap(), 1);
+        assert!(start.elapsed() >= delay);
+    }
+    #[test]
+    fn test_nonblocking_recv() {
+        let delay = Duration::from_millis(30);
+        let (a, b) = PipeDevice::pair();
+        b.set_nonblocking(true);
+        let b = Impaired::new(b, 0).with_recv(Impairment { delay, ..Impairment::default() });
+        assert!(b.is_nonblocking());
+        let start = Instant::now();
+        a.send(&[3]).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(b.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        let due = b.next_recv_deadline().unwrap();
+        assert!(due >= start + delay);
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        assert_eq!(b.recv(&mut buf).unwrap(), 1);
+        assert_eq!(b.next_recv_deadline(), None);
+    }
+    #[test]
+    fn test_held_frame_released() {
+        let reorder = Impairment { reorder: 1.0, ..Impairment::default() };
+        let (a, b) = PipeDevice::pair();
+        b.set_nonblocking(true);
+        let b = Impaired::new(b, 0).with_recv(reorder);
+        a.send(&[4]).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(b.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        thread::sleep(b.next_recv_deadline().unwrap().saturating_duration_since(Instant::now()));
+        assert_eq!(b.recv(&mut buf).unwrap(), 1);
+
+        let (a, b) = PipeDevice::pair();
+        let a = Impaired::new(a, 0).with_send(reorder);
+        a.send(&[5]).unwrap();
+        assert_eq!(a.pending(), 1);
+        thread::sleep(a.next_deadline().unwrap().saturating_duration_since(Instant::now()));
+        assert_eq!(a.pump().unwrap(), 1);
+        assert_eq!(drain(&b), vec![vec![5]]);
+    }
+    #[test]
+    fn test_send_error() {
+        let (a, b) = PipeDevice::pair();
+        let a = Impaired::new(a, 0).with_send(Impairment { duplicate: 1.0, ..Impairment::default() });
+        drop(b);
+        // both copies are taken in, the refused one waits for the next call
+        a.send(&[6]).unwrap();
+        assert_eq!(a.pending(), 2);
+        assert_eq!(a.send(&[7]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(a.pending(), 2);
+        assert_eq!(a.pump().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -1,18 +1,116 @@
 use std::io;
-use std::os::raw::c_char;
-use libc;
 
 pub mod bpf;
+pub mod filter;
+pub mod iface;
+pub mod impair;
 pub mod tuntap;
+pub mod udp_tunnel;
 pub mod raw_socket;
+pub mod shaper;
+pub mod pipe;
+pub mod pcap;
+pub mod poll;
+pub mod qemu_socket;
+pub mod stats;
+pub mod packet_mmap;
+pub mod xdp;
+#[cfg(feature = "async")]
+pub mod async_io;
 
 pub trait Device {
     fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
     fn send(&self, buf: &[u8]) -> io::Result<usize>;
+
+    // whether recv returns WouldBlock instead of waiting. the default batch methods only
+    // go past the first frame when this is true, so the default of false is always safe
+    fn is_nonblocking(&self) -> bool {
+        false
+    }
+
+    // receive up to bufs.len() frames, lengths are returned in buffer order.
+    // a blocking device returns a single frame. the batch ends early when the device
+    // runs dry or fails after the first frame. that error is discarded so the frames
+    // already received are not lost, a device that is still broken fails the next call
+    fn recv_batch(&self, bufs: &mut [&mut [u8]]) -> io::Result<Vec<usize>> {
+        let mut lens = Vec::with_capacity(bufs.len());
+        for buf in bufs.iter_mut() {
+            if !lens.is_empty() && !self.is_nonblocking() {
+                break;
+            }
+            match retry(|| self.recv(buf)) {
+                Ok(len) => lens.push(len),
+                Err(e) if lens.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(lens)
+    }
+
+    // send frames in order, stops at the first failure after the first frame, discards
+    // that error and leaves the rest to the next call
+    fn send_batch(&self, bufs: &[&[u8]]) -> io::Result<Vec<usize>> {
+        let mut lens = Vec::with_capacity(bufs.len());
+        for buf in bufs.iter() {
+            match retry(|| self.send(buf)) {
+                Ok(len) => lens.push(len),
+                Err(e) if lens.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(lens)
+    }
 }
 
-#[derive(Debug)]
-pub struct ifreq {
-    ifr_name: [libc::c_char; libc::IF_NAMESIZE],
-    ifr_flags: libc::c_int,
-}
\ No newline at end of file
+// a signal during the call is not a device error
+fn retry<F: FnMut() -> io::Result<usize>>(mut f: F) -> io::Result<usize> {
+    loop {
+        match f() {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // yields frames until it runs out, then fails like a downed interface
+    struct Flaky {
+        frames: Cell<usize>,
+    }
+
+    impl Device for Flaky {
+        fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.frames.get() {
+                0 => Err(io::Error::from_raw_os_error(libc::ENETDOWN)),
+                n => {
+                    self.frames.set(n - 1);
+                    buf[0] = n as u8;
+                    Ok(1)
+                }
+            }
+        }
+
+        fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn is_nonblocking(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_recv_batch_error() {
+        let dev = Flaky { frames: Cell::new(2) };
+        let mut bufs = [[0u8; 4]; 4];
+        let mut refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+        assert_eq!(dev.recv_batch(&mut refs).unwrap(), vec![1, 1]);
+        // the device is still down, so the next call reports it
+        let err = dev.recv_batch(&mut refs).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENETDOWN));
+    }
+}
@@ -0,0 +1,591 @@
+use std::cell::Cell;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::time::Duration;
+use byteorder::{ByteOrder, NativeEndian};
+use nix::unistd::close;
+use crate::device::Device;
+use crate::device::iface::interface_index;
+use crate::device::raw_socket::{eth_p_all, PACKET_OUTGOING};
+
+pub const PACKET_RX_RING: libc::c_int = 5;
+pub const PACKET_VERSION: libc::c_int = 10;
+pub const PACKET_TX_RING: libc::c_int = 13;
+
+pub const TPACKET_V2: libc::c_int = 1;
+pub const TPACKET_V3: libc::c_int = 2;
+
+pub const TP_STATUS_KERNEL: u32 = 0;
+pub const TP_STATUS_USER: u32 = 1;
+pub const TP_STATUS_AVAILABLE: u32 = 0;
+pub const TP_STATUS_SEND_REQUEST: u32 = 1;
+pub const TP_STATUS_SENDING: u32 = 2;
+pub const TP_STATUS_WRONG_FORMAT: u32 = 4;
+
+pub const TPACKET_ALIGNMENT: usize = 16;
+
+mod field {
+    use std::ops::Range;
+
+    // struct tpacket_block_desc with tpacket_hdr_v1
+    pub const BLOCK_STATUS: Range<usize> = 8..12;
+    pub const NUM_PKTS: Range<usize> = 12..16;
+    pub const OFFSET_TO_FIRST_PKT: Range<usize> = 16..20;
+
+    // struct tpacket3_hdr
+    pub const V3_NEXT_OFFSET: Range<usize> = 0..4;
+    pub const V3_SEC: Range<usize> = 4..8;
+    pub const V3_NSEC: Range<usize> = 8..12;
+    pub const V3_SNAPLEN: Range<usize> = 12..16;
+    pub const V3_LEN: Range<usize> = 16..20;
+    pub const V3_MAC: Range<usize> = 24..26;
+    pub const V3_HEADER_LENGTH: usize = 48;
+
+    // struct tpacket2_hdr
+    pub const V2_STATUS: Range<usize> = 0..4;
+    pub const V2_LEN: Range<usize> = 4..8;
+    pub const V2_SNAPLEN: Range<usize> = 8..12;
+    pub const V2_DATA: usize = 32;
+
+    // struct sockaddr_ll following the frame header
+    pub const SLL_PKTTYPE: usize = 10;
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct tpacket_req {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct tpacket_req3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RingConfig {
+    // rx block size, a multiple of the page size
+    pub block_size: u32,
+    pub block_nr: u32,
+    // largest frame, a multiple of TPACKET_ALIGNMENT
+    pub frame_size: u32,
+    // hand a partly filled rx block to user space after this many milliseconds
+    pub retire_blk_tov: u32,
+    pub tx_block_size: u32,
+    pub tx_block_nr: u32,
+}
+
+impl Default for RingConfig {
+    fn default() -> Self {
+        RingConfig {
+            block_size: 1 << 20,
+            block_nr: 8,
+            frame_size: 2048,
+            retire_blk_tov: 10,
+            tx_block_size: 1 << 16,
+            tx_block_nr: 4,
+        }
+    }
+}
+
+// a received frame living in the rx ring
+#[derive(Debug, Copy, Clone)]
+pub struct RingFrame<'a> {
+    pub data: &'a [u8],
+    // length on the wire, data may be shorter
+    pub len: usize,
+    pub sec: u32,
+    pub nsec: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Cursor {
+    block: usize,
+    // offset of the next packet and packets left in the open block
+    next: usize,
+    left: u32,
+}
+
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_LOCKED, fd, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr: ptr as *mut u8, len })
+    }
+
+    // the kernel writes into the ring concurrently, only touch what status hands over
+    unsafe fn slice(&self, offset: usize, len: usize) -> &[u8] {
+        std::slice::from_raw_parts(self.ptr.add(offset), len)
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slice_mut(&self, offset: usize, len: usize) -> &mut [u8] {
+        std::slice::from_raw_parts_mut(self.ptr.add(offset), len)
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.ptr.add(offset) as *const u32) }
+    }
+
+    fn write_u32(&self, offset: usize, val: u32) {
+        unsafe { ptr::write_volatile(self.ptr.add(offset) as *mut u32, val) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len); }
+    }
+}
+
+// AF_PACKET device with a TPACKET_V3 rx ring and a TPACKET_V2 tx ring
+#[derive(Debug)]
+pub struct RingDevice {
+    rx_fd: RawFd,
+    tx_fd: RawFd,
+    rx: Mapping,
+    tx: Mapping,
+    config: RingConfig,
+    cursor: Cell<Cursor>,
+    tx_frame: Cell<usize>,
+    tx_rejected: Cell<u64>,
+}
+
+// the rings are owned by the device, Cell keeps it !Sync
+unsafe impl Send for RingDevice {}
+
+impl AsRawFd for RingDevice {
+    // the rx socket, readable when a block is ready
+    fn as_raw_fd(&self) -> RawFd {
+        self.rx_fd
+    }
+}
+
+impl RingDevice {
+    pub fn new(name: &str) -> io::Result<RingDevice> {
+        RingDevice::with_config(name, RingConfig::default())
+    }
+
+    pub fn with_config(name: &str, config: RingConfig) -> io::Result<RingDevice> {
+        if config.frame_size == 0
+            || !(config.frame_size as usize).is_multiple_of(TPACKET_ALIGNMENT)
+            || !config.block_size.is_multiple_of(config.frame_size)
+            || !config.tx_block_size.is_multiple_of(config.frame_size) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let ifindex = interface_index(name)?;
+
+        let rx_fd = open_socket(ifindex, eth_p_all())?;
+        let rx = match setup_rx_ring(rx_fd, &config) {
+            Ok(rx) => rx,
+            Err(e) => {
+                let _ = close(rx_fd);
+                return Err(e);
+            }
+        };
+        // protocol 0 never receives, the socket is only used to transmit
+        let tx_fd = match open_socket(ifindex, 0) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = close(rx_fd);
+                return Err(e);
+            }
+        };
+        let tx = match setup_tx_ring(tx_fd, &config) {
+            Ok(tx) => tx,
+            Err(e) => {
+                let _ = close(rx_fd);
+                let _ = close(tx_fd);
+                return Err(e);
+            }
+        };
+        Ok(RingDevice {
+            rx_fd,
+            tx_fd,
+            rx,
+            tx,
+            config,
+            cursor: Cell::new(Cursor { block: 0, next: 0, left: 0 }),
+            tx_frame: Cell::new(0),
+            tx_rejected: Cell::new(0),
+        })
+    }
+
+    pub fn config(&self) -> &RingConfig {
+        &self.config
+    }
+
+    // frames the kernel refused to transmit, noticed when their slot comes round again
+    pub fn tx_rejected(&self) -> u64 {
+        self.tx_rejected.get()
+    }
+
+    // wait for the next block of frames, None on timeout
+    pub fn next_block(&mut self, timeout: Option<Duration>) -> io::Result<Option<Block<'_>>> {
+        if self.cursor.get().left == 0 && !self.open_block(timeout)? {
+            return Ok(None);
+        }
+        Ok(Some(Block { dev: self }))
+    }
+
+    fn block_offset(&self, block: usize) -> usize {
+        block * self.config.block_size as usize
+    }
+
+    // make the next block current, false when none became ready in time
+    fn open_block(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        loop {
+            let mut cursor = self.cursor.get();
+            let base = self.block_offset(cursor.block);
+            if self.rx.read_u32(base + field::BLOCK_STATUS.start) & TP_STATUS_USER == 0 {
+                if !wait(self.rx_fd, libc::POLLIN, timeout)? {
+                    return Ok(false);
+                }
+                continue;
+            }
+            fence(Ordering::Acquire);
+            let header = unsafe { self.rx.slice(base, field::OFFSET_TO_FIRST_PKT.end) };
+            cursor.left = NativeEndian::read_u32(&header[field::NUM_PKTS]);
+            cursor.next = NativeEndian::read_u32(&header[field::OFFSET_TO_FIRST_PKT]) as usize;
+            self.cursor.set(cursor);
+            if cursor.left > 0 {
+                return Ok(true);
+            }
+            self.release_block();
+        }
+    }
+
+    // take the next frame of the open block, None for frames we transmitted
+    fn take_frame(&self) -> Option<RingFrame<'_>> {
+        let mut cursor = self.cursor.get();
+        let base = self.block_offset(cursor.block);
+        let offset = base + cursor.next;
+        let header = unsafe { self.rx.slice(offset, field::V3_HEADER_LENGTH + field::SLL_PKTTYPE + 1) };
+        let next = NativeEndian::read_u32(&header[field::V3_NEXT_OFFSET]) as usize;
+        let snaplen = NativeEndian::read_u32(&header[field::V3_SNAPLEN]) as usize;
+        let mac = NativeEndian::read_u16(&header[field::V3_MAC]) as usize;
+        let frame = RingFrame {
+            data: unsafe { self.rx.slice(offset + mac, snaplen) },
+            len: NativeEndian::read_u32(&header[field::V3_LEN]) as usize,
+            sec: NativeEndian::read_u32(&header[field::V3_SEC]),
+            nsec: NativeEndian::read_u32(&header[field::V3_NSEC]),
+        };
+        let outgoing = header[field::V3_HEADER_LENGTH + field::SLL_PKTTYPE] == PACKET_OUTGOING;
+
+        cursor.next += next;
+        cursor.left -= 1;
+        self.cursor.set(cursor);
+        if outgoing { None } else { Some(frame) }
+    }
+
+    fn release_block(&self) {
+        let mut cursor = self.cursor.get();
+        fence(Ordering::Release);
+        self.rx.write_u32(self.block_offset(cursor.block) + field::BLOCK_STATUS.start, TP_STATUS_KERNEL);
+        cursor.block = (cursor.block + 1) % self.config.block_nr as usize;
+        cursor.left = 0;
+        self.cursor.set(cursor);
+    }
+
+    fn tx_frame_nr(&self) -> usize {
+        (self.config.tx_block_size / self.config.frame_size * self.config.tx_block_nr) as usize
+    }
+
+    // copy buf into the next free tx slot without kicking the kernel
+    fn queue(&self, buf: &[u8]) -> io::Result<usize> {
+        let frame_size = self.config.frame_size as usize;
+        if buf.len() > frame_size - field::V2_DATA {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let index = self.tx_frame.get();
+        let offset = index * frame_size;
+        loop {
+            let status = self.tx.read_u32(offset + field::V2_STATUS.start);
+            if status == TP_STATUS_AVAILABLE {
+                break;
+            }
+            if status & TP_STATUS_WRONG_FORMAT != 0 {
+                self.tx_rejected.set(self.tx_rejected.get() + 1);
+                break;
+            }
+            // the ring is full, push what is queued and wait for room
+            self.flush()?;
+            wait(self.tx_fd, libc::POLLOUT, None)?;
+        }
+        fence(Ordering::Acquire);
+        let frame = unsafe { self.tx.slice_mut(offset, frame_size) };
+        frame[field::V2_DATA..field::V2_DATA + buf.len()].copy_from_slice(buf);
+        NativeEndian::write_u32(&mut frame[field::V2_LEN], buf.len() as u32);
+        NativeEndian::write_u32(&mut frame[field::V2_SNAPLEN], buf.len() as u32);
+        fence(Ordering::Release);
+        self.tx.write_u32(offset + field::V2_STATUS.start, TP_STATUS_SEND_REQUEST);
+        self.tx_frame.set((index + 1) % self.tx_frame_nr());
+        Ok(buf.len())
+    }
+
+    // ask the kernel to transmit every queued frame
+    fn flush(&self) -> io::Result<()> {
+        let res = unsafe { libc::send(self.tx_fd, ptr::null(), 0, libc::MSG_DONTWAIT) };
+        if res == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Device for RingDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.cursor.get().left == 0 {
+                self.open_block(None)?;
+            }
+            let frame = self.take_frame().map(|frame| {
+                let len = frame.data.len().min(buf.len());
+                buf[..len].copy_from_slice(&frame.data[..len]);
+                len
+            });
+            if self.cursor.get().left == 0 {
+                self.release_block();
+            }
+            if let Some(len) = frame {
+                return Ok(len);
+            }
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.queue(buf)?;
+        self.flush()?;
+        Ok(len)
+    }
+
+    // fill the tx ring and kick the kernel once
+    fn send_batch(&self, bufs: &[&[u8]]) -> io::Result<Vec<usize>> {
+        let mut lens = Vec::with_capacity(bufs.len());
+        for buf in bufs.iter() {
+            match self.queue(buf) {
+                Ok(len) => lens.push(len),
+                Err(e) if lens.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        self.flush()?;
+        Ok(lens)
+    }
+}
+
+impl Drop for RingDevice {
+    fn drop(&mut self) {
+        let _ = close(self.rx_fd);
+        let _ = close(self.tx_fd);
+    }
+}
+
+// a block of the rx ring, handed back to the kernel on drop
+#[derive(Debug)]
+pub struct Block<'a> {
+    dev: &'a RingDevice,
+}
+
+impl<'a> Block<'a> {
+    pub fn frames(&mut self) -> Frames<'_> {
+        Frames { dev: self.dev }
+    }
+}
+
+impl<'a> Drop for Block<'a> {
+    // frames not iterated are dropped with the block
+    fn drop(&mut self) {
+        self.dev.release_block();
+    }
+}
+
+#[derive(Debug)]
+pub struct Frames<'b> {
+    dev: &'b RingDevice,
+}
+
+impl<'b> Iterator for Frames<'b> {
+    type Item = RingFrame<'b>;
+
+    fn next(&mut self) -> Option<RingFrame<'b>> {
+        while self.dev.cursor.get().left > 0 {
+            if let Some(frame) = self.dev.take_frame() {
+                return Some(frame);
+            }
+        }
+        None
+    }
+}
+
+// the protocol is only given to bind, so nothing from other interfaces is queued before
+fn open_socket(ifindex: libc::c_int, protocol: libc::c_int) -> io::Result<RawFd> {
+    let fd = unsafe {
+        let fd = libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0);
+        if fd == -1 { return Err(io::Error::last_os_error()) }
+        fd
+    };
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+    addr.sll_protocol = protocol as libc::c_ushort;
+    addr.sll_ifindex = ifindex;
+    let res = unsafe {
+        libc::bind(fd, &addr as *const _ as *const libc::sockaddr,
+                   mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t)
+    };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        let _ = close(fd);
+        return Err(err);
+    }
+    Ok(fd)
+}
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, val: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(fd, libc::SOL_PACKET, opt, val as *const T as *const libc::c_void,
+                         mem::size_of::<T>() as libc::socklen_t)
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn setup_rx_ring(fd: RawFd, config: &RingConfig) -> io::Result<Mapping> {
+    setsockopt(fd, PACKET_VERSION, &TPACKET_V3)?;
+    let req = tpacket_req3 {
+        tp_block_size: config.block_size,
+        tp_block_nr: config.block_nr,
+        tp_frame_size: config.frame_size,
+        tp_frame_nr: config.block_size / config.frame_size * config.block_nr,
+        tp_retire_blk_tov: config.retire_blk_tov,
+        ..Default::default()
+    };
+    setsockopt(fd, PACKET_RX_RING, &req)?;
+    Mapping::new(fd, (config.block_size * config.block_nr) as usize)
+}
+
+fn setup_tx_ring(fd: RawFd, config: &RingConfig) -> io::Result<Mapping> {
+    setsockopt(fd, PACKET_VERSION, &TPACKET_V2)?;
+    let req = tpacket_req {
+        tp_block_size: config.tx_block_size,
+        tp_block_nr: config.tx_block_nr,
+        tp_frame_size: config.frame_size,
+        tp_frame_nr: config.tx_block_size / config.frame_size * config.tx_block_nr,
+    };
+    setsockopt(fd, PACKET_TX_RING, &req)?;
+    Mapping::new(fd, (config.tx_block_size * config.tx_block_nr) as usize)
+}
+
+// poll a single fd, false on timeout
+fn wait(fd: RawFd, events: libc::c_short, timeout: Option<Duration>) -> io::Result<bool> {
+    let mut pfd = libc::pollfd { fd, events, revents: 0 };
+    let millis = match timeout {
+        Some(t) => t.as_millis().min(libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    };
+    let res = unsafe { libc::poll(&mut pfd, 1, millis) };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(true);
+        }
+        return Err(err);
+    }
+    Ok(res > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::netns::{readable, Namespace};
+
+    fn marked(tag: u8) -> [u8; 60] {
+        let mut frame = [0u8; 60];
+        frame[12..14].copy_from_slice(&[0x88, 0xb7]);
+        frame[14..18].copy_from_slice(&[0x71, 0x9a, 0x00, tag]);
+        frame
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = RingConfig { frame_size: 1000, ..RingConfig::default() };
+        assert_eq!(RingDevice::with_config("lo", config).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+    #[test]
+    fn test_ring_send_recv() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.run(|| RingDevice::new("lo")).unwrap().unwrap();
+        let frame = marked(1);
+        assert_eq!(dev.send(&frame).unwrap(), 60);
+        let mut buf = [0u8; 2048];
+        loop {
+            // frames left in the open block do not make the socket readable
+            if dev.cursor.get().left == 0 {
+                readable(&dev, Duration::from_secs(5)).unwrap();
+            }
+            let len = dev.recv(&mut buf).unwrap();
+            if buf[12..18] == frame[12..18] {
+                assert_eq!(len, 60);
+                break;
+            }
+        }
+    }
+    #[test]
+    fn test_tx_rejected() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.run(|| RingDevice::new("lo")).unwrap().unwrap();
+        // as the kernel leaves a slot whose frame it could not send
+        dev.tx.write_u32(field::V2_STATUS.start, TP_STATUS_WRONG_FORMAT);
+        assert_eq!(dev.send(&marked(2)).unwrap(), 60);
+        assert_eq!(dev.tx_rejected(), 1);
+    }
+    #[test]
+    fn test_ring_blocks() {
+        let ns = Namespace::new().unwrap();
+        let mut dev = ns.run(|| RingDevice::new("lo")).unwrap().unwrap();
+        let frames = [marked(0xa0), marked(0xa1), marked(0xa2)];
+        let refs: Vec<&[u8]> = frames.iter().map(|f| &f[..]).collect();
+        assert_eq!(dev.send_batch(&refs).unwrap(), vec![60, 60, 60]);
+        let mut seen = Vec::new();
+        while seen.len() < frames.len() {
+            let mut block = dev.next_block(Some(Duration::from_secs(1))).unwrap().unwrap();
+            for frame in block.frames() {
+                if frame.data[12..17] == frames[0][12..17] {
+                    assert_eq!(frame.len, 60);
+                    seen.push(frame.data[17]);
+                }
+            }
+        }
+        assert_eq!(seen, vec![0xa0, 0xa1, 0xa2]);
+        assert_ne!(dev.cursor.get().block, 0);
+    }
+}
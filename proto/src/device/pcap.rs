@@ -0,0 +1,446 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use crate::device::Device;
+
+pub const MAGIC_MICRO: u32 = 0xa1b2_c3d4;
+pub const MAGIC_NANO: u32 = 0xa1b2_3c4d;
+pub const VERSION_MAJOR: u16 = 2;
+pub const VERSION_MINOR: u16 = 4;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const DEFAULT_SNAPLEN: u32 = 65535;
+
+pub const HEADER_LENGTH: usize = 24;
+pub const RECORD_HEADER_LENGTH: usize = 16;
+
+mod field {
+    use std::ops::Range;
+
+    // global header
+    pub const MAGIC: Range<usize> = 0..4;
+    pub const VERSION_MAJOR: Range<usize> = 4..6;
+    pub const VERSION_MINOR: Range<usize> = 6..8;
+    pub const THISZONE: Range<usize> = 8..12;
+    pub const SIGFIGS: Range<usize> = 12..16;
+    pub const SNAPLEN: Range<usize> = 16..20;
+    pub const LINKTYPE: Range<usize> = 20..24;
+
+    // record header
+    pub const TS_SEC: Range<usize> = 0..4;
+    pub const TS_FRAC: Range<usize> = 4..8;
+    pub const INCL_LEN: Range<usize> = 8..12;
+    pub const ORIG_LEN: Range<usize> = 12..16;
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    fn read_u16(self, buf: &[u8]) -> u16 {
+        match self {
+            Endian::Little => LittleEndian::read_u16(buf),
+            Endian::Big => BigEndian::read_u16(buf),
+        }
+    }
+
+    fn read_u32(self, buf: &[u8]) -> u32 {
+        match self {
+            Endian::Little => LittleEndian::read_u32(buf),
+            Endian::Big => BigEndian::read_u32(buf),
+        }
+    }
+
+    fn write_u16(self, buf: &mut [u8], n: u16) {
+        match self {
+            Endian::Little => LittleEndian::write_u16(buf, n),
+            Endian::Big => BigEndian::write_u16(buf, n),
+        }
+    }
+
+    fn write_u32(self, buf: &mut [u8], n: u32) {
+        match self {
+            Endian::Little => LittleEndian::write_u32(buf, n),
+            Endian::Big => BigEndian::write_u32(buf, n),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Precision {
+    Micro,
+    Nano,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Header {
+    pub endian: Endian,
+    pub precision: Precision,
+    pub version_major: u16,
+    pub version_minor: u16,
+    pub snaplen: u32,
+    pub linktype: u32,
+}
+
+impl Header {
+    pub fn parse(buf: &[u8]) -> io::Result<Header> {
+        if buf.len() < HEADER_LENGTH {
+            return Err(invalid("pcap header too short"));
+        }
+        let (endian, precision) = match (
+            LittleEndian::read_u32(&buf[field::MAGIC]),
+            BigEndian::read_u32(&buf[field::MAGIC]),
+        ) {
+            (MAGIC_MICRO, _) => (Endian::Little, Precision::Micro),
+            (MAGIC_NANO, _) => (Endian::Little, Precision::Nano),
+            (_, MAGIC_MICRO) => (Endian::Big, Precision::Micro),
+            (_, MAGIC_NANO) => (Endian::Big, Precision::Nano),
+            _ => return Err(invalid("bad pcap magic number")),
+        };
+        let header = Header {
+            endian,
+            precision,
+            version_major: endian.read_u16(&buf[field::VERSION_MAJOR]),
+            version_minor: endian.read_u16(&buf[field::VERSION_MINOR]),
+            snaplen: endian.read_u32(&buf[field::SNAPLEN]),
+            linktype: endian.read_u32(&buf[field::LINKTYPE]),
+        };
+        if header.linktype != LINKTYPE_ETHERNET {
+            return Err(invalid(&format!("unsupported pcap linktype {}", header.linktype)));
+        }
+        Ok(header)
+    }
+
+    // in the byte order of self.endian, native unless parsed from a file
+    pub fn to_bytes(&self) -> [u8; HEADER_LENGTH] {
+        let mut buf = [0u8; HEADER_LENGTH];
+        let magic = match self.precision {
+            Precision::Micro => MAGIC_MICRO,
+            Precision::Nano => MAGIC_NANO,
+        };
+        let endian = self.endian;
+        endian.write_u32(&mut buf[field::MAGIC], magic);
+        endian.write_u16(&mut buf[field::VERSION_MAJOR], self.version_major);
+        endian.write_u16(&mut buf[field::VERSION_MINOR], self.version_minor);
+        endian.write_u32(&mut buf[field::THISZONE], 0);
+        endian.write_u32(&mut buf[field::SIGFIGS], 0);
+        endian.write_u32(&mut buf[field::SNAPLEN], self.snaplen);
+        endian.write_u32(&mut buf[field::LINKTYPE], self.linktype);
+        buf
+    }
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            endian: if cfg!(target_endian = "big") { Endian::Big } else { Endian::Little },
+            precision: Precision::Micro,
+            version_major: VERSION_MAJOR,
+            version_minor: VERSION_MINOR,
+            snaplen: DEFAULT_SNAPLEN,
+            linktype: LINKTYPE_ETHERNET,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Record {
+    pub ts_sec: u32,
+    // always nanoseconds regardless of the file precision
+    pub ts_nsec: u32,
+    pub orig_len: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct PcapReader<R: Read> {
+    inner: R,
+    header: Header,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut inner: R) -> io::Result<PcapReader<R>> {
+        let mut buf = [0u8; HEADER_LENGTH];
+        inner.read_exact(&mut buf)?;
+        Ok(PcapReader {
+            inner,
+            header: Header::parse(&buf)?,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    // returns None at the end of the capture
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut buf = [0u8; RECORD_HEADER_LENGTH];
+        // only a capture that stops between records ends cleanly
+        let mut got = 0;
+        while got < buf.len() {
+            match self.inner.read(&mut buf[got..]) {
+                Ok(0) if got == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated pcap record header")),
+                Ok(n) => got += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let endian = self.header.endian;
+        let incl_len = endian.read_u32(&buf[field::INCL_LEN]);
+        if incl_len > self.header.snaplen.max(DEFAULT_SNAPLEN) {
+            return Err(invalid(&format!("pcap record length {} exceeds snaplen", incl_len)));
+        }
+        let frac = endian.read_u32(&buf[field::TS_FRAC]);
+        let mut data = vec![0u8; incl_len as usize];
+        self.inner.read_exact(&mut data)?;
+        Ok(Some(Record {
+            ts_sec: endian.read_u32(&buf[field::TS_SEC]),
+            ts_nsec: match self.header.precision {
+                Precision::Micro => frac.saturating_mul(1000),
+                Precision::Nano => frac,
+            },
+            orig_len: endian.read_u32(&buf[field::ORIG_LEN]),
+            data,
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub struct PcapWriter<W: Write> {
+    inner: W,
+    header: Header,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(inner: W) -> io::Result<PcapWriter<W>> {
+        PcapWriter::with_header(inner, Header::default())
+    }
+
+    pub fn with_header(mut inner: W, header: Header) -> io::Result<PcapWriter<W>> {
+        if header.linktype != LINKTYPE_ETHERNET {
+            return Err(invalid(&format!("unsupported pcap linktype {}", header.linktype)));
+        }
+        inner.write_all(&header.to_bytes())?;
+        Ok(PcapWriter { inner, header })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    // frames longer than snaplen are truncated, orig_len keeps the real length
+    pub fn write_record(&mut self, ts_sec: u32, ts_nsec: u32, data: &[u8]) -> io::Result<()> {
+        let incl_len = data.len().min(self.header.snaplen as usize);
+        let frac = match self.header.precision {
+            Precision::Micro => ts_nsec / 1000,
+            Precision::Nano => ts_nsec,
+        };
+        let mut buf = [0u8; RECORD_HEADER_LENGTH];
+        let endian = self.header.endian;
+        endian.write_u32(&mut buf[field::TS_SEC], ts_sec);
+        endian.write_u32(&mut buf[field::TS_FRAC], frac);
+        endian.write_u32(&mut buf[field::INCL_LEN], incl_len as u32);
+        endian.write_u32(&mut buf[field::ORIG_LEN], data.len() as u32);
+        self.inner.write_all(&buf)?;
+        self.inner.write_all(&data[..incl_len])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[derive(Debug)]
+pub struct PcapDevice {
+    reader: Option<Mutex<PcapReader<BufReader<File>>>>,
+    writer: Option<Mutex<PcapWriter<BufWriter<File>>>>,
+}
+
+impl PcapDevice {
+    // replay an existing capture, send is rejected
+    pub fn open<P: AsRef<Path>>(input: P) -> io::Result<PcapDevice> {
+        Ok(PcapDevice {
+            reader: Some(Mutex::new(open_reader(input)?)),
+            writer: None,
+        })
+    }
+
+    // record sent frames, recv is rejected
+    pub fn create<P: AsRef<Path>>(output: P) -> io::Result<PcapDevice> {
+        Ok(PcapDevice {
+            reader: None,
+            writer: Some(Mutex::new(create_writer(output)?)),
+        })
+    }
+
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> io::Result<PcapDevice> {
+        Ok(PcapDevice {
+            reader: Some(Mutex::new(open_reader(input)?)),
+            writer: Some(Mutex::new(create_writer(output)?)),
+        })
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        match self.writer {
+            Some(ref w) => w.lock().unwrap().flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Device for PcapDevice {
+    // returns 0 once the capture is exhausted
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let reader = self.reader.as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "no input capture"))?;
+        match reader.lock().unwrap().next_record()? {
+            Some(record) => {
+                let len = record.data.len().min(buf.len());
+                buf[..len].copy_from_slice(&record.data[..len]);
+                Ok(len)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let writer = self.writer.as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "no output capture"))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        writer.lock().unwrap().write_record(now.as_secs() as u32, now.subsec_nanos(), buf)?;
+        Ok(buf.len())
+    }
+}
+
+impl Drop for PcapDevice {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn open_reader<P: AsRef<Path>>(path: P) -> io::Result<PcapReader<BufReader<File>>> {
+    PcapReader::new(BufReader::new(File::open(path)?))
+}
+
+fn create_writer<P: AsRef<Path>>(path: P) -> io::Result<PcapWriter<BufWriter<File>>> {
+    PcapWriter::new(BufWriter::new(File::create(path)?))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // big endian, nanosecond precision, one 4 byte record
+    static BE_NANO_BYTES: [u8; 44] =
+        [0xa1, 0xb2, 0x3c, 0x4d,
+            0x00, 0x02, 0x00, 0x04,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x40,
+            0x00, 0x00, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x0a,
+            0x00, 0x00, 0x00, 0x7b,
+            0x00, 0x00, 0x00, 0x04,
+            0x00, 0x00, 0x00, 0x3c,
+            0xde, 0xad, 0xbe, 0xef];
+
+    #[test]
+    fn test_read_big_endian_nano() {
+        let mut r = PcapReader::new(Cursor::new(BE_NANO_BYTES.to_vec())).unwrap();
+        assert_eq!(r.header().endian, Endian::Big);
+        assert_eq!(r.header().precision, Precision::Nano);
+        assert_eq!(r.header().snaplen, 64);
+        let record = r.next_record().unwrap().unwrap();
+        assert_eq!(record.ts_sec, 10);
+        assert_eq!(record.ts_nsec, 123);
+        assert_eq!(record.orig_len, 60);
+        assert_eq!(record.data, vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(r.next_record().unwrap(), None);
+    }
+    #[test]
+    fn test_truncated_record_header() {
+        let mut r = PcapReader::new(Cursor::new(BE_NANO_BYTES[..HEADER_LENGTH + 10].to_vec())).unwrap();
+        assert_eq!(r.next_record().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let mut r = PcapReader::new(Cursor::new(BE_NANO_BYTES[..42].to_vec())).unwrap();
+        assert_eq!(r.next_record().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+    #[test]
+    fn test_big_endian_round_trip() {
+        let header = Header::parse(&BE_NANO_BYTES[..HEADER_LENGTH]).unwrap();
+        assert_eq!(&header.to_bytes()[..], &BE_NANO_BYTES[..HEADER_LENGTH]);
+        let mut w = PcapWriter::with_header(Vec::new(), header).unwrap();
+        w.write_record(10, 123, &[0xde, 0xad, 0xbe, 0xef]).unwrap();
+        let mut bytes = w.into_inner();
+        // the writer records the full frame length, the fixture a 60 byte one
+        bytes[39] = 0x3c;
+        assert_eq!(bytes, BE_NANO_BYTES.to_vec());
+    }
+    #[test]
+    fn test_reject_linktype() {
+        let mut bytes = BE_NANO_BYTES.to_vec();
+        bytes[23] = 101; // LINKTYPE_RAW
+        let err = PcapReader::new(Cursor::new(bytes)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+    #[test]
+    fn test_reject_magic() {
+        let err = Header::parse(&[0u8; HEADER_LENGTH]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+    #[test]
+    fn test_write_read_micro() {
+        let mut w = PcapWriter::new(Vec::new()).unwrap();
+        w.write_record(1, 2_000, &[1, 2, 3]).unwrap();
+        let bytes = w.into_inner();
+        let mut r = PcapReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(r.header().precision, Precision::Micro);
+        assert_eq!(r.header().snaplen, DEFAULT_SNAPLEN);
+        let record = r.next_record().unwrap().unwrap();
+        assert_eq!(record.ts_sec, 1);
+        assert_eq!(record.ts_nsec, 2_000);
+        assert_eq!(record.data, vec![1, 2, 3]);
+    }
+    #[test]
+    fn test_write_snaplen() {
+        let header = Header { snaplen: 2, ..Header::default() };
+        let mut w = PcapWriter::with_header(Vec::new(), header).unwrap();
+        w.write_record(0, 0, &[1, 2, 3]).unwrap();
+        let mut r = PcapReader::new(Cursor::new(w.into_inner())).unwrap();
+        let record = r.next_record().unwrap().unwrap();
+        assert_eq!(record.data, vec![1, 2]);
+        assert_eq!(record.orig_len, 3);
+    }
+    #[test]
+    fn test_pcap_device() {
+        let path = std::env::temp_dir().join(format!("rusproto-pcap-{}.pcap", std::process::id()));
+        {
+            let dev = PcapDevice::create(&path).unwrap();
+            assert_eq!(dev.send(&[0xaa; 60]).unwrap(), 60);
+            assert_eq!(dev.send(&[0xbb; 14]).unwrap(), 14);
+        }
+        let dev = PcapDevice::open(&path).unwrap();
+        let mut buf = [0u8; 256];
+        assert_eq!(dev.recv(&mut buf).unwrap(), 60);
+        assert_eq!(dev.recv(&mut buf).unwrap(), 14);
+        assert_eq!(&buf[..14], &[0xbb; 14]);
+        assert_eq!(dev.recv(&mut buf).unwrap(), 0);
+        assert_eq!(dev.send(&buf[..14]).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
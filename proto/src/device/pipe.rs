@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use crate::device::Device;
+
+// one direction of the pipe
+#[derive(Debug, Default)]
+struct Queue {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    ready: Condvar,
+    closed: AtomicBool,
+}
+
+impl Queue {
+    fn push(&self, frame: Vec<u8>) -> io::Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let mut frames = self.frames.lock().unwrap();
+        frames.push_back(frame);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    fn close(&self) {
+        // hold the lock so a receiver can not miss the wakeup
+        let _frames = self.frames.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        self.ready.notify_all();
+    }
+}
+
+// in-memory device connected to its peer returned by PipeDevice::pair
+#[derive(Debug)]
+pub struct PipeDevice {
+    rx: Arc<Queue>,
+    tx: Arc<Queue>,
+    nonblocking: AtomicBool,
+}
+
+impl PipeDevice {
+    pub fn pair() -> (PipeDevice, PipeDevice) {
+        let a = Arc::new(Queue::default());
+        let b = Arc::new(Queue::default());
+        (
+            PipeDevice {
+                rx: a.clone(),
+                tx: b.clone(),
+                nonblocking: AtomicBool::new(false),
+            },
+            PipeDevice {
+                rx: b,
+                tx: a,
+                nonblocking: AtomicBool::new(false),
+            },
+        )
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::SeqCst)
+    }
+
+    pub fn is_nonblocking(&self) -> bool {
+        self.nonblocking.load(Ordering::SeqCst)
+    }
+
+    // number of frames waiting to be received
+    pub fn pending(&self) -> usize {
+        self.rx.frames.lock().unwrap().len()
+    }
+}
+
+impl Device for PipeDevice {
+    // frames longer than buf are truncated like a packet socket
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut frames = self.rx.frames.lock().unwrap();
+        loop {
+            if let Some(frame) = frames.pop_front() {
+                let len = frame.len().min(buf.len());
+                buf[..len].copy_from_slice(&frame[..len]);
+                return Ok(len);
+            }
+            if self.rx.closed.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
+            if self.is_nonblocking() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            frames = self.rx.ready.wait(frames).unwrap();
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.push(buf.to_vec())?;
+        Ok(buf.len())
+    }
+
+    fn is_nonblocking(&self) -> bool {
+        PipeDevice::is_nonblocking(self)
+    }
+}
+
+impl Drop for PipeDevice {
+    fn drop(&mut self) {
+        self.rx.close();
+        self.tx.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_pipe_send_recv() {
+        let (a, b) = PipeDevice::pair();
+        assert_eq!(a.send(&[1, 2, 3, 4]).unwrap(), 4);
+        assert_eq!(b.send(&[5, 6]).unwrap(), 2);
+        let mut buf = [0u8; 16];
+        assert_eq!(b.recv(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &[1, 2, 3, 4]);
+        assert_eq!(a.recv(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[5, 6]);
+    }
+    #[test]
+    fn test_pipe_truncate() {
+        let (a, b) = PipeDevice::pair();
+        a.send(&[1, 2, 3, 4]).unwrap();
+        let mut buf = [0u8; 2];
+        assert_eq!(b.recv(&mut buf).unwrap(), 2);
+        assert_eq!(buf, [1, 2]);
+        assert_eq!(b.pending(), 0);
+    }
+    #[test]
+    fn test_pipe_nonblocking() {
+        let (a, b) = PipeDevice::pair();
+        b.set_nonblocking(true);
+        let mut buf = [0u8; 16];
+        assert_eq!(b.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        a.send(&[0xff]).unwrap();
+        assert_eq!(b.recv(&mut buf).unwrap(), 1);
+    }
+    #[test]
+    fn test_pipe_closed() {
+        let (a, b) = PipeDevice::pair();
+        a.send(&[0xaa]).unwrap();
+        drop(a);
+        let mut buf = [0u8; 16];
+        assert_eq!(b.recv(&mut buf).unwrap(), 1);
+        assert_eq!(b.recv(&mut buf).unwrap(), 0);
+        assert_eq!(b.send(&[0xaa]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+    #[test]
+    fn test_pipe_batch() {
+        let (a, b) = PipeDevice::pair();
+        assert_eq!(a.send_batch(&[&[1], &[2, 2], &[3, 3, 3]]).unwrap(), vec![1, 2, 3]);
+        b.set_nonblocking(true);
+        let mut bufs = [[0u8; 4]; 4];
+        let mut refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+        assert_eq!(b.recv_batch(&mut refs).unwrap(), vec![1, 2, 3]);
+        assert_eq!(&bufs[2][..3], &[3, 3, 3]);
+        let mut refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+        assert_eq!(b.recv_batch(&mut refs).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+    #[test]
+    fn test_pipe_blocking_batch() {
+        let (a, b) = PipeDevice::pair();
+        a.send_batch(&[&[1], &[2, 2]]).unwrap();
+        let mut bufs = [[0u8; 4]; 4];
+        let mut refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+        // a blocking device does not wait to fill the batch
+        assert_eq!(b.recv_batch(&mut refs).unwrap(), vec![1]);
+        assert_eq!(b.recv_batch(&mut refs).unwrap(), vec![2]);
+    }
+    #[test]
+    fn test_pipe_thread() {
+        let (a, b) = PipeDevice::pair();
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 16];
+            let len = b.recv(&mut buf).unwrap();
+            b.send(&buf[..len]).unwrap();
+        });
+        a.send(&[1, 2, 3]).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(a.recv(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        handle.join().unwrap();
+    }
+}
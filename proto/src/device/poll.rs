@@ -0,0 +1,397 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+use nix::unistd::close;
+use crate::device::Device;
+
+const MAX_EVENTS: usize = 64;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
+pub struct Token(pub usize);
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
+pub struct TimerId(pub u64);
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Event {
+    // the device has a frame to recv, or an error to report
+    Readable(Token),
+    Timer(TimerId),
+}
+
+#[derive(Debug)]
+struct Timer {
+    interval: Option<Duration>,
+}
+
+// epoll based readiness loop over devices and timers
+#[derive(Debug)]
+pub struct Poll {
+    epfd: RawFd,
+    fds: HashMap<Token, RawFd>,
+    deadlines: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    timers: HashMap<TimerId, Timer>,
+    next_timer: u64,
+}
+
+impl AsRawFd for Poll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epfd
+    }
+}
+
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        let epfd = unsafe {
+            let epfd = libc::epoll_create1(libc::EPOLL_CLOEXEC);
+            if epfd == -1 { return Err(io::Error::last_os_error()) }
+            epfd
+        };
+        Ok(Poll {
+            epfd,
+            fds: HashMap::new(),
+            deadlines: BinaryHeap::new(),
+            timers: HashMap::new(),
+            next_timer: 0,
+        })
+    }
+
+    pub fn register<D: Device + AsRawFd>(&mut self, dev: &D, token: Token) -> io::Result<()> {
+        if self.fds.contains_key(&token) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let fd = dev.as_raw_fd();
+        self.ctl(libc::EPOLL_CTL_ADD, fd, token)?;
+        self.fds.insert(token, fd);
+        Ok(())
+    }
+
+    pub fn deregister(&mut self, token: Token) -> io::Result<()> {
+        let fd = self.fds.remove(&token)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        self.ctl(libc::EPOLL_CTL_DEL, fd, token)
+    }
+
+    // fire once after the delay
+    pub fn add_timer(&mut self, after: Duration) -> TimerId {
+        self.schedule(after, None)
+    }
+
+    // fire every interval until cancelled
+    pub fn add_interval(&mut self, interval: Duration) -> TimerId {
+        self.schedule(interval, Some(interval))
+    }
+
+    pub fn cancel_timer(&mut self, id: TimerId) -> bool {
+        self.timers.remove(&id).is_some()
+    }
+
+    // wait for readiness or expired timers, a None timeout waits until something happens
+    pub fn poll(&mut self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        events.clear();
+        let now = Instant::now();
+        let timeout = match (timeout, self.next_deadline()) {
+            (Some(t), Some(d)) => Some(t.min(d.saturating_duration_since(now))),
+            (Some(t), None) => Some(t),
+            (None, Some(d)) => Some(d.saturating_duration_since(now)),
+            (None, None) => None,
+        };
+        let millis = match timeout {
+            // round up so an unexpired timer does not spin
+            Some(t) => t.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+
+        let mut ready = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let n = unsafe { libc::epoll_wait(self.epfd, ready.as_mut_ptr(), MAX_EVENTS as libc::c_int, millis) };
+        if n == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        for ev in ready.iter().take(n.max(0) as usize) {
+            events.push(Event::Readable(Token(ev.u64 as usize)));
+        }
+        self.expire(Instant::now(), events);
+        Ok(())
+    }
+
+    fn schedule(&mut self, after: Duration, interval: Option<Duration>) -> TimerId {
+        // a zero interval would be due again immediately and never let expire return
+        let interval = interval.map(|i| i.max(Duration::from_nanos(1)));
+        let id = TimerId(self.next_timer);
+        self.next_timer += 1;
+        self.timers.insert(id, Timer { interval });
+        self.deadlines.push(Reverse((Instant::now() + after, id)));
+        id
+    }
+
+    fn next_deadline(&mut self) -> Option<Instant> {
+        // drop cancelled timers lazily
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if self.timers.contains_key(&id) {
+                return Some(deadline);
+            }
+            self.deadlines.pop();
+        }
+        None
+    }
+
+    fn expire(&mut self, now: Instant, events: &mut Vec<Event>) {
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop();
+            match self.timers.get(&id).map(|t| t.interval) {
+                Some(Some(interval)) => {
+                    // a timer that fell behind skips the missed ticks
+                    let next = (deadline + interval).max(now + interval);
+                    self.deadlines.push(Reverse((next, id)));
+                }
+                Some(None) => {
+                    self.timers.remove(&id);
+                }
+                None => continue,
+            }
+            events.push(Event::Timer(id));
+        }
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: RawFd, token: Token) -> io::Result<()> {
+        let mut ev = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token.0 as u64,
+        };
+        let res = unsafe { libc::epoll_ctl(self.epfd, op, fd, &mut ev) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Poll {
+    fn drop(&mut self) {
+        let _ = close(self.epfd);
+    }
+}
+
+type Handler<'a> = Box<dyn FnMut() -> io::Result<()> + 'a>;
+
+// dispatches readiness and timers to callbacks
+pub struct Reactor<'a> {
+    poll: Poll,
+    readers: HashMap<Token, Handler<'a>>,
+    timers: HashMap<TimerId, Handler<'a>>,
+    next_token: usize,
+    events: Vec<Event>,
+}
+
+impl<'a> Reactor<'a> {
+    pub fn new() -> io::Result<Reactor<'a>> {
+        Ok(Reactor {
+            poll: Poll::new()?,
+            readers: HashMap::new(),
+            timers: HashMap::new(),
+            next_token: 0,
+            events: Vec::with_capacity(MAX_EVENTS),
+        })
+    }
+
+    // f is called with dev each time it becomes readable
+    pub fn register<D, F>(&mut self, dev: &'a D, mut f: F) -> io::Result<Token>
+        where D: Device + AsRawFd, F: FnMut(&'a D) -> io::Result<()> + 'a
+    {
+        let token = Token(self.next_token);
+        self.poll.register(dev, token)?;
+        self.next_token += 1;
+        self.readers.insert(token, Box::new(move || f(dev)));
+        Ok(token)
+    }
+
+    pub fn deregister(&mut self, token: Token) -> io::Result<()> {
+        self.readers.remove(&token);
+        self.poll.deregister(token)
+    }
+
+    pub fn after<F>(&mut self, delay: Duration, f: F) -> TimerId
+        where F: FnMut() -> io::Result<()> + 'a
+    {
+        let id = self.poll.add_timer(delay);
+        self.timers.insert(id, Box::new(f));
+        id
+    }
+
+    pub fn every<F>(&mut self, interval: Duration, f: F) -> TimerId
+        where F: FnMut() -> io::Result<()> + 'a
+    {
+        let id = self.poll.add_interval(interval);
+        self.timers.insert(id, Box::new(f));
+        id
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        self.timers.remove(&id);
+        self.poll.cancel_timer(id)
+    }
+
+    // returns the number of callbacks run. every event is dispatched even if a callback
+    // fails, the first error is returned afterwards
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> io::Result<usize> {
+        self.poll.poll(&mut self.events, timeout)?;
+        let mut dispatched = 0;
+        let mut first_err = None;
+        for event in self.events.iter() {
+            let res = match *event {
+                Event::Readable(token) => match self.readers.get_mut(&token) {
+                    Some(f) => f(),
+                    None => continue,
+                },
+                // a one-shot timer is gone from poll once fired, drop its handler before running it
+                Event::Timer(id) if !self.poll.timers.contains_key(&id) => match self.timers.remove(&id) {
+                    Some(mut f) => f(),
+                    None => continue,
+                },
+                Event::Timer(id) => match self.timers.get_mut(&id) {
+                    Some(f) => f(),
+                    None => continue,
+                },
+            };
+            dispatched += 1;
+            if let Err(e) = res {
+                first_err.get_or_insert(e);
+            }
+        }
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(dispatched),
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            self.run_once(None)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::os::unix::net::UnixDatagram;
+
+    // socketpair backed device usable without privileges
+    struct SocketDevice(UnixDatagram);
+
+    impl Device for SocketDevice {
+        fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buf)
+        }
+
+        fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf)
+        }
+    }
+
+    impl AsRawFd for SocketDevice {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    fn pair() -> (SocketDevice, SocketDevice) {
+        let (a, b) = UnixDatagram::pair().unwrap();
+        (SocketDevice(a), SocketDevice(b))
+    }
+
+    #[test]
+    fn test_poll_readable() {
+        let (a, b) = pair();
+        let mut poll = Poll::new().unwrap();
+        poll.register(&b, Token(7)).unwrap();
+        let mut events = Vec::new();
+        poll.poll(&mut events, Some(Duration::from_millis(0))).unwrap();
+        assert!(events.is_empty());
+        a.send(&[1, 2, 3]).unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events, vec![Event::Readable(Token(7))]);
+        assert_eq!(poll.register(&b, Token(7)).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        poll.deregister(Token(7)).unwrap();
+        poll.poll(&mut events, Some(Duration::from_millis(0))).unwrap();
+        assert!(events.is_empty());
+    }
+    #[test]
+    fn test_poll_timers() {
+        let mut poll = Poll::new().unwrap();
+        let late = poll.add_timer(Duration::from_millis(20));
+        let early = poll.add_timer(Duration::from_millis(5));
+        let cancelled = poll.add_timer(Duration::from_millis(1));
+        assert!(poll.cancel_timer(cancelled));
+        let mut events = Vec::new();
+        let mut fired = Vec::new();
+        while fired.len() < 2 {
+            poll.poll(&mut events, None).unwrap();
+            fired.extend(events.iter().cloned());
+        }
+        assert_eq!(fired, vec![Event::Timer(early), Event::Timer(late)]);
+        assert!(!poll.cancel_timer(late));
+    }
+    #[test]
+    fn test_reactor_forward() {
+        let (a, b) = pair();
+        let (c, d) = pair();
+        let forwarded = Cell::new(0);
+        let ticks = Cell::new(0);
+        {
+            let mut reactor = Reactor::new().unwrap();
+            reactor.register(&b, |dev| {
+                let mut buf = [0u8; 64];
+                let len = dev.recv(&mut buf)?;
+                c.send(&buf[..len])?;
+                forwarded.set(forwarded.get() + 1);
+                Ok(())
+            }).unwrap();
+            let tick = reactor.every(Duration::from_millis(1), || {
+                ticks.set(ticks.get() + 1);
+                Ok(())
+            });
+            a.send(&[0xaa, 0xbb]).unwrap();
+            while forwarded.get() == 0 || ticks.get() < 2 {
+                reactor.run_once(Some(Duration::from_secs(1))).unwrap();
+            }
+            assert!(reactor.cancel(tick));
+        }
+        let mut buf = [0u8; 64];
+        assert_eq!(d.recv(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[0xaa, 0xbb]);
+    }
+    #[test]
+    fn test_zero_interval() {
+        let mut poll = Poll::new().unwrap();
+        let id = poll.add_interval(Duration::from_secs(0));
+        let mut events = Vec::new();
+        for _ in 0..3 {
+            poll.poll(&mut events, Some(Duration::from_millis(1))).unwrap();
+            assert_eq!(events, vec![Event::Timer(id)]);
+        }
+    }
+    #[test]
+    fn test_reactor_error() {
+        let ran = Cell::new(0);
+        let mut reactor = Reactor::new().unwrap();
+        reactor.after(Duration::from_millis(0), || Err(io::ErrorKind::Other.into()));
+        reactor.after(Duration::from_millis(0), || {
+            ran.set(ran.get() + 1);
+            Ok(())
+        });
+        assert_eq!(reactor.run_once(None).unwrap_err().kind(), io::ErrorKind::Other);
+        // the second timer still ran and both one-shot handlers are gone
+        assert_eq!(ran.get(), 1);
+        assert!(reactor.timers.is_empty());
+    }
+}
@@ -0,0 +1,317 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Mutex;
+use byteorder::{BigEndian, ByteOrder};
+use crate::device::Device;
+
+pub const LENGTH_PREFIX: usize = 4;
+// qemu's NET_BUFSIZE, longer frames are a framing error
+pub const MAX_FRAME_LENGTH: usize = 4096 + 65536;
+
+#[derive(Debug)]
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(s) => (&*s).read(buf),
+            Stream::Tcp(s) => (&*s).read(buf),
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(s) => (&*s).write(buf),
+            Stream::Tcp(s) => (&*s).write(buf),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Unix(s) => s.set_nonblocking(nonblocking),
+            Stream::Tcp(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Unix(s) => s.as_raw_fd(),
+            Stream::Tcp(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+// frames prefixed with a 4 byte big endian length, as qemu's -netdev stream and socket use
+#[derive(Debug)]
+pub struct StreamDevice {
+    stream: Stream,
+    // bytes read but not yet returned as a frame
+    rx: Mutex<Vec<u8>>,
+    // the unwritten rest of a frame a full socket buffer cut short
+    tx: Mutex<Vec<u8>>,
+}
+
+impl AsRawFd for StreamDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl StreamDevice {
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<StreamDevice> {
+        Ok(StreamDevice::from_unix(UnixStream::connect(path)?))
+    }
+
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<StreamDevice> {
+        Ok(StreamDevice::from_tcp(TcpStream::connect(addr)?))
+    }
+
+    pub fn from_unix(stream: UnixStream) -> StreamDevice {
+        StreamDevice::with_stream(Stream::Unix(stream))
+    }
+
+    pub fn from_tcp(stream: TcpStream) -> StreamDevice {
+        // frames are small and latency sensitive
+        let _ = stream.set_nodelay(true);
+        StreamDevice::with_stream(Stream::Tcp(stream))
+    }
+
+    fn with_stream(stream: Stream) -> StreamDevice {
+        StreamDevice {
+            stream,
+            rx: Mutex::new(Vec::new()),
+            tx: Mutex::new(Vec::new()),
+        }
+    }
+
+    // a nonblocking send that fills the socket buffer keeps the rest of the frame, it is
+    // written before any later frame and sends fail with WouldBlock until then
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    // write the rest of a frame cut short, WouldBlock if the socket still has no room
+    pub fn flush(&self) -> io::Result<()> {
+        let mut tx = self.tx.lock().unwrap();
+        self.flush_locked(&mut tx)
+    }
+
+    fn flush_locked(&self, tx: &mut Vec<u8>) -> io::Result<()> {
+        let n = self.write_some(tx)?;
+        tx.drain(..n);
+        if !tx.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(())
+    }
+
+    // writes what the socket takes, only a nonblocking one stops short
+    fn write_some(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            match self.stream.write(&buf[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+
+    // bytes still missing from the frame at the head of the buffer
+    fn missing(rx: &[u8]) -> io::Result<usize> {
+        if rx.len() < LENGTH_PREFIX {
+            return Ok(LENGTH_PREFIX - rx.len());
+        }
+        let len = BigEndian::read_u32(&rx[..LENGTH_PREFIX]) as usize;
+        if len > MAX_FRAME_LENGTH {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        Ok(LENGTH_PREFIX + len - rx.len())
+    }
+}
+
+impl Device for StreamDevice {
+    // fails with UnexpectedEof once the peer has closed the connection, 0 is an empty
+    // frame. reads never go past the current frame, so the socket stays readable while
+    // more frames are queued
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut rx = self.rx.lock().unwrap();
+        let mut chunk = [0u8; 8192];
+        loop {
+            let missing = StreamDevice::missing(&rx)?;
+            if missing == 0 && rx.len() >= LENGTH_PREFIX {
+                let len = (rx.len() - LENGTH_PREFIX).min(buf.len());
+                buf[..len].copy_from_slice(&rx[LENGTH_PREFIX..LENGTH_PREFIX + len]);
+                rx.clear();
+                return Ok(len);
+            }
+            let n = self.stream.read(&mut chunk[..missing.min(8192)])?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "peer closed the stream"));
+            }
+            rx.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > MAX_FRAME_LENGTH {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut frame = Vec::with_capacity(LENGTH_PREFIX + buf.len());
+        frame.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+        frame.extend_from_slice(buf);
+        let mut tx = self.tx.lock().unwrap();
+        self.flush_locked(&mut tx)?;
+        let n = self.write_some(&frame)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        // the peer already has the start of the frame, so it counts as sent
+        tx.extend_from_slice(&frame[n..]);
+        Ok(buf.len())
+    }
+}
+
+// waits for a vm to connect, e.g. qemu -netdev stream,addr.type=unix,addr.path=...
+#[derive(Debug)]
+pub enum StreamListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl StreamListener {
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<StreamListener> {
+        Ok(StreamListener::Unix(UnixListener::bind(path)?))
+    }
+
+    pub fn bind_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<StreamListener> {
+        Ok(StreamListener::Tcp(TcpListener::bind(addr)?))
+    }
+
+    pub fn accept(&self) -> io::Result<StreamDevice> {
+        match self {
+            StreamListener::Unix(l) => Ok(StreamDevice::from_unix(l.accept()?.0)),
+            StreamListener::Tcp(l) => Ok(StreamDevice::from_tcp(l.accept()?.0)),
+        }
+    }
+}
+
+impl AsRawFd for StreamListener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            StreamListener::Unix(l) => l.as_raw_fd(),
+            StreamListener::Tcp(l) => l.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_framing() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let dev = StreamDevice::from_unix(a);
+        dev.send(&[0xaa, 0xbb, 0xcc]).unwrap();
+        let mut raw = [0u8; 7];
+        (&b).read_exact(&mut raw).unwrap();
+        assert_eq!(raw, [0, 0, 0, 3, 0xaa, 0xbb, 0xcc]);
+
+        // a frame split over several writes only comes out once complete
+        dev.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 16];
+        (&b).write_all(&[0, 0, 0, 2, 0x11]).unwrap();
+        assert_eq!(dev.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        (&b).write_all(&[0x22, 0, 0, 0, 1, 0x33]).unwrap();
+        assert_eq!(dev.recv(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[0x11, 0x22]);
+        assert_eq!(dev.recv(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 0x33);
+        (&b).write_all(&[0, 0, 0, 0]).unwrap();
+        assert_eq!(dev.recv(&mut buf).unwrap(), 0);
+
+        drop(b);
+        assert_eq!(dev.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+    #[test]
+    fn test_full_socket_buffer() {
+        const LEN: usize = 50000;
+        let (a, b) = UnixStream::pair().unwrap();
+        let dev = StreamDevice::from_unix(a);
+        dev.set_nonblocking(true).unwrap();
+        let mut sent = 0;
+        loop {
+            match dev.send(&[sent as u8; LEN]) {
+                Ok(_) => sent += 1,
+                Err(e) => {
+                    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+                    break;
+                }
+            }
+        }
+        assert!(!dev.tx.lock().unwrap().is_empty());
+        // drain the peer until the frame cut short is finished, then send one more
+        b.set_nonblocking(true).unwrap();
+        let mut raw = Vec::new();
+        let mut chunk = [0u8; 65536];
+        let mut read = |raw: &mut Vec<u8>| {
+            while let Ok(n) = (&b).read(&mut chunk) {
+                raw.extend_from_slice(&chunk[..n]);
+            }
+        };
+        loop {
+            read(&mut raw);
+            if dev.flush().is_ok() {
+                break;
+            }
+        }
+        dev.send(&[sent as u8; LEN]).unwrap();
+        read(&mut raw);
+        let frames: Vec<_> = raw.chunks(LENGTH_PREFIX + LEN).collect();
+        assert_eq!(frames.len(), sent + 1);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame[..LENGTH_PREFIX], (LEN as u32).to_be_bytes());
+            assert!(frame[LENGTH_PREFIX..].iter().all(|&b| b == i as u8));
+        }
+    }
+    #[test]
+    fn test_bad_length() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let dev = StreamDevice::from_unix(a);
+        (&b).write_all(&[0xff, 0xff, 0xff, 0xff]).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(dev.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+    #[test]
+    fn test_tcp_listener() {
+        let listener = StreamListener::bind_tcp("127.0.0.1:0").unwrap();
+        let addr = match &listener {
+            StreamListener::Tcp(l) => l.local_addr().unwrap(),
+            _ => unreachable!(),
+        };
+        let handle = thread::spawn(move || {
+            let dev = listener.accept().unwrap();
+            let mut buf = [0u8; 1514];
+            let len = dev.recv(&mut buf).unwrap();
+            dev.send(&buf[..len]).unwrap();
+        });
+        let dev = StreamDevice::connect_tcp(addr).unwrap();
+        let frame = [0x5a; 1514];
+        dev.send(&frame).unwrap();
+        let mut buf = [0u8; 1514];
+        assert_eq!(dev.recv(&mut buf).unwrap(), 1514);
+        assert_eq!(&buf[..], &frame[..]);
+        handle.join().unwrap();
+    }
+}
@@ -1,14 +1,33 @@
 use std::io;
-use nix::sys::socket;
+use std::mem;
 use std::os::unix::io::RawFd;
 use std::os::unix::io::AsRawFd;
-use crate::device::{Device, ifreq};
-use nix::unistd::{read, write};
+use crate::device::Device;
+use crate::device::filter::Program;
+use crate::device::iface::interface_index;
+use nix::unistd::{write, close};
+
+
+pub const PACKET_ADD_MEMBERSHIP: libc::c_int = 1;
+pub const PACKET_DROP_MEMBERSHIP: libc::c_int = 2;
+pub const PACKET_MR_PROMISC: libc::c_ushort = 1;
+
+pub const PACKET_OUTGOING: u8 = 4;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct packet_mreq {
+    mr_ifindex: libc::c_int,
+    mr_type: libc::c_ushort,
+    mr_alen: libc::c_ushort,
+    mr_address: [libc::c_uchar; 8],
+}
 
 #[derive(Debug)]
 pub struct RawSocketDevice {
     fd: RawFd,
-    ifreq: ifreq,
+    name: String,
+    ifindex: libc::c_int,
 }
 
 impl AsRawFd for RawSocketDevice {
@@ -18,25 +37,113 @@ impl AsRawFd for RawSocketDevice {
 }
 
 impl RawSocketDevice {
+    // open an AF_PACKET socket bound to the interface name
     pub fn new(name: &str) -> io::Result<RawSocketDevice> {
+        let ifindex = interface_index(name)?;
+        // protocol 0 receives nothing until bind names the protocol and the interface,
+        // so no frame from another interface gets queued in between
         let soc = unsafe {
-            let lower = libc::socket(libc::AF_PACKET, libc::SOCK_RAW,
-                                     libc::ETH_P_ALL);
+            let lower = libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0);
             if lower == -1 { return Err(io::Error::last_os_error()) }
             lower
         };
-        Ok(RawSocketDevice{
+        let dev = RawSocketDevice{
             fd: soc,
-            ifreq: ifreq_for(name),
-        })
+            name: name.to_string(),
+            ifindex,
+        };
+        dev.bind()?;
+        Ok(dev)
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn ifindex(&self) -> i32 {
+        self.ifindex
+    }
+
+    pub fn set_promiscuous(&self, enable: bool) -> io::Result<()> {
+        let mreq = packet_mreq {
+            mr_ifindex: self.ifindex,
+            mr_type: PACKET_MR_PROMISC,
+            ..Default::default()
+        };
+        let opt = if enable { PACKET_ADD_MEMBERSHIP } else { PACKET_DROP_MEMBERSHIP };
+        let res = unsafe {
+            libc::setsockopt(self.fd, libc::SOL_PACKET, opt,
+                             &mreq as *const _ as *const libc::c_void,
+                             mem::size_of::<packet_mreq>() as libc::socklen_t)
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // only frames the program accepts are queued on the socket
+    pub fn attach_filter(&self, prog: &Program) -> io::Result<()> {
+        let fprog = prog.as_fprog();
+        let res = unsafe {
+            libc::setsockopt(self.fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER,
+                             &fprog as *const _ as *const libc::c_void,
+                             mem::size_of_val(&fprog) as libc::socklen_t)
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn detach_filter(&self) -> io::Result<()> {
+        // the value is ignored but the kernel still wants an int
+        let val: libc::c_int = 0;
+        let res = unsafe {
+            libc::setsockopt(self.fd, libc::SOL_SOCKET, libc::SO_DETACH_FILTER,
+                             &val as *const _ as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn bind(&self) -> io::Result<()> {
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = eth_p_all() as libc::c_ushort;
+        addr.sll_ifindex = self.ifindex;
+        let res = unsafe {
+            libc::bind(self.fd, &addr as *const _ as *const libc::sockaddr,
+                       mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t)
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
     }
 }
 
 impl Device for RawSocketDevice {
+    // frames this socket transmitted itself are skipped
     fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
-        let len = read(self.fd, buf)
-            .map_err(|_| io::Error::last_os_error())?;
-        Ok(len)
+        loop {
+            let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+            let mut addrlen = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            let len = unsafe {
+                libc::recvfrom(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0,
+                               &mut addr as *mut _ as *mut libc::sockaddr, &mut addrlen)
+            };
+            if len == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if addr.sll_pkttype == PACKET_OUTGOING {
+                continue;
+            }
+            return Ok(len as usize);
+        }
     }
 
     fn send(&self, buf: &[u8]) -> io::Result<usize> {
@@ -44,24 +151,185 @@ impl Device for RawSocketDevice {
             .map_err(|_| io::Error::last_os_error())?;
         Ok(len)
     }
+
+    // blocks for the first frame only, then takes whatever is queued
+    fn recv_batch(&self, bufs: &mut [&mut [u8]]) -> io::Result<Vec<usize>> {
+        if bufs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut addrs: Vec<libc::sockaddr_ll> = vec![unsafe { mem::zeroed() }; bufs.len()];
+        let mut iovs: Vec<libc::iovec> = bufs.iter_mut().map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }).collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs.iter_mut().zip(addrs.iter_mut()).map(|(iov, addr)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        }).collect();
+        loop {
+            let n = unsafe {
+                libc::recvmmsg(self.fd, msgs.as_mut_ptr(), msgs.len() as _,
+                               libc::MSG_WAITFORONE, std::ptr::null_mut())
+            };
+            if n == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let received: Vec<(usize, usize)> = msgs.iter().take(n as usize).enumerate()
+                .filter(|(i, _)| addrs[*i].sll_pkttype != PACKET_OUTGOING)
+                .map(|(i, msg)| (i, msg.msg_len as usize))
+                .collect();
+            if received.is_empty() {
+                continue;
+            }
+            // pack the kept frames to the front of bufs
+            let mut lens = Vec::with_capacity(received.len());
+            for (j, &(i, len)) in received.iter().enumerate() {
+                if i != j {
+                    let (head, tail) = bufs.split_at_mut(i);
+                    head[j][..len].copy_from_slice(&tail[0][..len]);
+                }
+                lens.push(len);
+            }
+            return Ok(lens);
+        }
+    }
+
+    fn send_batch(&self, bufs: &[&[u8]]) -> io::Result<Vec<usize>> {
+        if bufs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut iovs: Vec<libc::iovec> = bufs.iter().map(|buf| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }).collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs.iter_mut().map(|iov| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        }).collect();
+        let n = unsafe { libc::sendmmsg(self.fd, msgs.as_mut_ptr(), msgs.len() as _, 0) };
+        if n == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(msgs.iter().take(n as usize).map(|msg| msg.msg_len as usize).collect())
+    }
 }
 
-fn ifreq_for(name: &str) -> ifreq {
-    let mut ifreq = ifreq {
-        ifr_name: [0; libc::IF_NAMESIZE],
-        ifr_flags: 0
-    };
-    for (i, byte) in name.as_bytes().iter().enumerate() {
-        ifreq.ifr_name[i] = *byte as libc::c_char
+impl Drop for RawSocketDevice {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
     }
-    ifreq
+}
+
+// ETH_P_ALL in network byte order
+pub(crate) fn eth_p_all() -> libc::c_int {
+    (libc::ETH_P_ALL as u16).to_be() as libc::c_int
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::device::Device;
+    use std::time::Duration;
+    use crate::util::netns::{readable, Namespace};
+
     #[test]
     fn test_new_raw_socket() {
-        let dev = super::RawSocketDevice::new("test").unwrap();
+        let ns = Namespace::new().unwrap();
+        let dev = ns.raw_socket("lo").unwrap();
         assert_ne!(dev.fd, -1);
+        assert_eq!(dev.ifindex(), 1);
+    }
+    #[test]
+    fn test_unknown_interface() {
+        let ns = Namespace::new().unwrap();
+        assert!(ns.raw_socket("rusproto-none").is_err());
+    }
+    #[test]
+    fn test_promiscuous() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.raw_socket("lo").unwrap();
+        dev.set_promiscuous(true).unwrap();
+        dev.set_promiscuous(false).unwrap();
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_skip_outgoing() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.raw_socket("lo").unwrap();
+        let mut frame = [0u8; 60];
+        frame[12..14].copy_from_slice(&[0x88, 0xb5]); // local experimental ethertype
+        frame[14..18].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        dev.send(&frame).unwrap();
+        let mut buf = [0u8; 1500];
+        let mut seen = 0;
+        unsafe { libc::fcntl(dev.fd, libc::F_SETFL, libc::O_NONBLOCK) };
+        loop {
+            match dev.recv(&mut buf) {
+                Ok(len) if buf[12..18] == frame[12..18] => {
+                    assert_eq!(len, frame.len());
+                    seen += 1;
+                }
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+        // loopback delivers an outgoing and an incoming copy
+        assert_eq!(seen, 1);
+    }
+    #[test]
+    fn test_batch() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.raw_socket("lo").unwrap();
+        let mut frames = [[0u8; 60]; 3];
+        for (i, frame) in frames.iter_mut().enumerate() {
+            frame[12..14].copy_from_slice(&[0x88, 0xb6]);
+            frame[14..18].copy_from_slice(&[0xba, 0x7c, 0x00, i as u8]);
+        }
+        let refs: Vec<&[u8]> = frames.iter().map(|f| &f[..]).collect();
+        assert_eq!(dev.send_batch(&refs).unwrap(), vec![60, 60, 60]);
+
+        let mut seen = Vec::new();
+        let mut bufs = [[0u8; 1500]; 8];
+        while seen.len() < frames.len() {
+            let mut refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+            readable(&dev, Duration::from_secs(5)).unwrap();
+            let lens = dev.recv_batch(&mut refs).unwrap();
+            for (buf, len) in bufs.iter().zip(lens) {
+                if buf[12..17] == frames[0][12..17] {
+                    assert_eq!(len, 60);
+                    seen.push(buf[17]);
+                }
+            }
+        }
+        assert_eq!(seen, vec![0, 1, 2]);
+    }
+    #[test]
+    fn test_attach_filter() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.raw_socket("lo").unwrap();
+        dev.attach_filter(&crate::device::filter::Program::arp()).unwrap();
+        let mut other = [0u8; 60];
+        other[12..14].copy_from_slice(&[0x88, 0xb7]);
+        other[14..18].copy_from_slice(&[0xf1, 0x17, 0xe2, 0x00]);
+        let mut arp = [0u8; 60];
+        arp[12..14].copy_from_slice(&[0x08, 0x06]);
+        arp[14..18].copy_from_slice(&[0xf1, 0x17, 0xe2, 0x00]);
+        dev.send(&other).unwrap();
+        dev.send(&arp).unwrap();
+        let mut buf = [0u8; 1500];
+        // frames queued before the filter was attached may still come first
+        loop {
+            readable(&dev, Duration::from_secs(5)).unwrap();
+            dev.recv(&mut buf).unwrap();
+            assert_ne!(buf[12..18], other[12..18]);
+            if buf[12..18] == arp[12..18] {
+                break;
+            }
+        }
+        dev.detach_filter().unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::device::Device;
+
+// a limit of zero disables that bucket
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Rate {
+    pub bits_per_sec: u64,
+    // bytes that may be sent back to back after an idle period
+    pub burst_bytes: u64,
+    pub packets_per_sec: u64,
+    pub burst_packets: u64,
+}
+
+impl Rate {
+    pub fn bits(bits_per_sec: u64, burst_bytes: u64) -> Rate {
+        Rate { bits_per_sec, burst_bytes, ..Rate::default() }
+    }
+
+    pub fn packets(packets_per_sec: u64, burst_packets: u64) -> Rate {
+        Rate { packets_per_sec, burst_packets, ..Rate::default() }
+    }
+}
+
+// what to do with a frame sent while the buckets are empty
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Policy {
+    Drop,
+    // hold up to limit frames and send them as tokens refill, drop beyond that
+    Queue { limit: usize },
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ShaperStats {
+    pub sent_packets: u64,
+    pub sent_bytes: u64,
+    pub dropped_packets: u64,
+    pub dropped_bytes: u64,
+    pub queued: usize,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: u64, now: Instant) -> Option<Bucket> {
+        if rate == 0 {
+            return None;
+        }
+        let burst = burst.max(1) as f64;
+        Some(Bucket { rate: rate as f64, burst, tokens: burst, last: now })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    // a cost above the burst size passes on a full bucket and leaves it in debt
+    fn wait(&self, cost: f64) -> Duration {
+        let missing = cost.min(self.burst) - self.tokens;
+        if missing <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+
+    fn take(&mut self, cost: f64) {
+        self.tokens -= cost;
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    bits: Option<Bucket>,
+    packets: Option<Bucket>,
+    queue: VecDeque<Vec<u8>>,
+    stats: ShaperStats,
+}
+
+impl State {
+    // time until a frame of len bytes may be sent
+    fn wait(&mut self, len: usize, now: Instant) -> Duration {
+        let mut wait = Duration::from_secs(0);
+        if let Some(b) = self.bits.as_mut() {
+            b.refill(now);
+            wait = wait.max(b.wait(len as f64 * 8.0));
+        }
+        if let Some(b) = self.packets.as_mut() {
+            b.refill(now);
+            wait = wait.max(b.wait(1.0));
+        }
+        wait
+    }
+
+    // charged once the inner device took the frame
+    fn take(&mut self, len: usize) {
+        if let Some(b) = self.bits.as_mut() {
+            b.take(len as f64 * 8.0);
+        }
+        if let Some(b) = self.packets.as_mut() {
+            b.take(1.0);
+        }
+        self.stats.sent_packets += 1;
+        self.stats.sent_bytes += len as u64;
+    }
+
+    fn drop_frame(&mut self, len: usize) {
+        self.stats.dropped_packets += 1;
+        self.stats.dropped_bytes += len as u64;
+    }
+}
+
+// token bucket shaping of the frames sent through the wrapped device, recv is untouched
+#[derive(Debug)]
+pub struct Shaped<D: Device> {
+    inner: D,
+    policy: Policy,
+    state: Mutex<State>,
+}
+
+impl<D: Device> Shaped<D> {
+    pub fn new(dev: D, rate: Rate, policy: Policy) -> Shaped<D> {
+        let now = Instant::now();
+        Shaped {
+            inner: dev,
+            policy,
+            state: Mutex::new(State {
+                bits: Bucket::new(rate.bits_per_sec, rate.burst_bytes * 8, now),
+                packets: Bucket::new(rate.packets_per_sec, rate.burst_packets, now),
+                queue: VecDeque::new(),
+                stats: ShaperStats::default(),
+            }),
+        }
+    }
+
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    pub fn stats(&self) -> ShaperStats {
+        let state = self.state.lock().unwrap();
+        ShaperStats { queued: state.queue.len(), ..state.stats }
+    }
+
+    // when pump can send the head of the queue
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+        let len = state.queue.front()?.len();
+        let now = Instant::now();
+        Some(now + state.wait(len, now))
+    }
+
+    // send the queued frames the buckets allow, returns how many were sent
+    pub fn pump(&self) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        self.pump_locked(&mut state)
+    }
+
+    // wait until the queue is empty
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while let Some(len) = state.queue.front().map(|f| f.len()) {
+            let wait = state.wait(len, Instant::now());
+            if wait > Duration::from_secs(0) {
+                thread::sleep(wait);
+            }
+            self.pump_locked(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn pump_locked(&self, state: &mut State) -> io::Result<usize> {
+        let mut sent = 0;
+        while let Some(len) = state.queue.front().map(|f| f.len()) {
+            if state.wait(len, Instant::now()) > Duration::from_secs(0) {
+                break;
+            }
+            // a frame the device refused stays at the head for the next pump
+            self.inner.send(state.queue.front().unwrap())?;
+            state.queue.pop_front();
+            state.take(len);
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+impl<D: Device + AsRawFd> AsRawFd for Shaped<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<D: Device> Device for Shaped<D> {
+    fn is_nonblocking(&self) -> bool {
+        self.inner.is_nonblocking()
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(buf)
+    }
+
+    // dropped and queued frames are reported as sent, see stats for what happened.
+    // a queued frame the device refuses stays at the head and pump reports the error,
+    // the new frame is queued or dropped behind it
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let _ = self.pump_locked(&mut state);
+        let len = buf.len();
+        if state.queue.is_empty() && state.wait(len, Instant::now()) == Duration::from_secs(0) {
+            let sent = self.inner.send(buf)?;
+            state.take(len);
+            return Ok(sent);
+        }
+        match self.policy {
+            Policy::Queue { limit } if state.queue.len() < limit => state.queue.push_back(buf.to_vec()),
+            _ => state.drop_frame(len),
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::pipe::PipeDevice;
+
+    fn count(dev: &PipeDevice) -> usize {
+        dev.set_nonblocking(true);
+        let mut buf = [0u8; 1500];
+        let mut n = 0;
+        while dev.recv(&mut buf).is_ok() {
+            n += 1;
+        }
+        n
+    }
+
+    #[test]
+    fn test_tail_drop() {
+        let (a, b) = PipeDevice::pair();
+        let a = Shaped::new(a, Rate::bits(8000, 1000), Policy::Drop);
+        for _ in 0..10 {
+            assert_eq!(a.send(&[0u8; 200]).unwrap(), 200);
+        }
+        assert_eq!(count(&b), 5);
+        let stats = a.stats();
+        assert_eq!((stats.sent_packets, stats.sent_bytes), (5, 1000));
+        assert_eq!((stats.dropped_packets, stats.dropped_bytes), (5, 1000));
+    }
+    #[test]
+    fn test_packet_rate() {
+        let (a, b) = PipeDevice::pair();
+        let a = Shaped::new(a, Rate::packets(10, 2), Policy::Drop);
+        for _ in 0..5 {
+            a.send(&[0u8; 60]).unwrap();
+        }
+        assert_eq!(count(&b), 2);
+        thread::sleep(Duration::from_millis(120));
+        a.send(&[0u8; 60]).unwrap();
+        assert_eq!(count(&b), 1);
+    }
+    #[test]
+    fn test_oversized_frame() {
+        let (a, b) = PipeDevice::pair();
+        let a = Shaped::new(a, Rate::bits(8000, 100), Policy::Drop);
+        a.send(&[0u8; 1500]).unwrap();
+        a.send(&[0u8; 60]).unwrap();
+        // the first frame passes on the full bucket and leaves it in debt
+        assert_eq!(count(&b), 1);
+    }
+    #[test]
+    fn test_queue() {
+        let (a, b) = PipeDevice::pair();
+        let a = Shaped::new(a, Rate::packets(100, 1), Policy::Queue { limit: 2 });
+        for _ in 0..4 {
+            a.send(&[0u8; 60]).unwrap();
+        }
+        assert_eq!(count(&b), 1);
+        let stats = a.stats();
+        assert_eq!((stats.queued, stats.dropped_packets), (2, 1));
+        assert!(a.next_deadline().is_some());
+        let start = Instant::now();
+        a.flush().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(15));
+        assert_eq!(count(&b), 2);
+        assert_eq!(a.stats().sent_packets, 3);
+        assert_eq!(a.next_deadline(), None);
+    }
+    #[test]
+    fn test_send_error() {
+        let (a, b) = PipeDevice::pair();
+        let a = Shaped::new(a, Rate::packets(100, 1), Policy::Queue { limit: 2 });
+        a.send(&[0u8; 60]).unwrap();
+        a.send(&[0u8; 60]).unwrap();
+        drop(b);
+        thread::sleep(Duration::from_millis(15));
+        assert_eq!(a.pump().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        // the refused frame is still queued and cost no tokens
+        let stats = a.stats();
+        assert_eq!((stats.sent_packets, stats.queued), (1, 1));
+        assert_eq!(a.next_deadline().unwrap().saturating_duration_since(Instant::now()), Duration::from_secs(0));
+        // new frames go behind it by the policy instead of failing
+        a.send(&[0u8; 60]).unwrap();
+        a.send(&[0u8; 60]).unwrap();
+        let stats = a.stats();
+        assert_eq!((stats.sent_packets, stats.queued, stats.dropped_packets), (1, 2, 1));
+        assert_eq!(a.pump().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
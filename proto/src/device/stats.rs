@@ -0,0 +1,209 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::device::Device;
+
+// upper bounds of the frame length histogram buckets, the last bucket takes the rest
+pub const HISTOGRAM_BOUNDS: [usize; 6] = [64, 127, 255, 511, 1023, 1518];
+pub const HISTOGRAM_BUCKETS: usize = HISTOGRAM_BOUNDS.len() + 1;
+
+fn bucket(len: usize) -> usize {
+    HISTOGRAM_BOUNDS.iter().position(|&bound| len <= bound).unwrap_or(HISTOGRAM_BOUNDS.len())
+}
+
+// counters of one direction at the time of the snapshot
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Counters {
+    pub packets: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub would_block: u64,
+    // zero length results, such as a closed pipe, which are not packets
+    pub empty: u64,
+    pub histogram: [u64; HISTOGRAM_BUCKETS],
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Stats {
+    pub rx: Counters,
+    pub tx: Counters,
+}
+
+#[derive(Debug, Default)]
+struct AtomicCounters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    would_block: AtomicU64,
+    empty: AtomicU64,
+    histogram: [AtomicU64; HISTOGRAM_BUCKETS],
+}
+
+impl AtomicCounters {
+    fn record(&self, res: &io::Result<usize>) {
+        match res {
+            Ok(len) => self.add(*len),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.would_block.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn add(&self, len: usize) {
+        if len == 0 {
+            self.empty.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.histogram[bucket(len)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // load or, with reset, take the counters
+    fn snapshot(&self, reset: bool) -> Counters {
+        let get = |c: &AtomicU64| if reset { c.swap(0, Ordering::Relaxed) } else { c.load(Ordering::Relaxed) };
+        let mut histogram = [0; HISTOGRAM_BUCKETS];
+        for (h, c) in histogram.iter_mut().zip(self.histogram.iter()) {
+            *h = get(c);
+        }
+        Counters {
+            packets: get(&self.packets),
+            bytes: get(&self.bytes),
+            errors: get(&self.errors),
+            would_block: get(&self.would_block),
+            empty: get(&self.empty),
+            histogram,
+        }
+    }
+}
+
+// counts the traffic passing through the wrapped device
+#[derive(Debug)]
+pub struct Counted<D: Device> {
+    inner: D,
+    rx: AtomicCounters,
+    tx: AtomicCounters,
+}
+
+impl<D: Device> Counted<D> {
+    pub fn new(dev: D) -> Counted<D> {
+        Counted {
+            inner: dev,
+            rx: AtomicCounters::default(),
+            tx: AtomicCounters::default(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            rx: self.rx.snapshot(false),
+            tx: self.tx.snapshot(false),
+        }
+    }
+
+    // returns the counters accumulated since the previous reset
+    pub fn reset(&self) -> Stats {
+        Stats {
+            rx: self.rx.snapshot(true),
+            tx: self.tx.snapshot(true),
+        }
+    }
+}
+
+impl<D: Device + AsRawFd> AsRawFd for Counted<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<D: Device> Device for Counted<D> {
+    fn is_nonblocking(&self) -> bool {
+        self.inner.is_nonblocking()
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.inner.recv(buf);
+        self.rx.record(&res);
+        res
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let res = self.inner.send(buf);
+        self.tx.record(&res);
+        res
+    }
+
+    fn recv_batch(&self, bufs: &mut [&mut [u8]]) -> io::Result<Vec<usize>> {
+        let res = self.inner.recv_batch(bufs);
+        record_batch(&self.rx, &res);
+        res
+    }
+
+    fn send_batch(&self, bufs: &[&[u8]]) -> io::Result<Vec<usize>> {
+        let res = self.inner.send_batch(bufs);
+        record_batch(&self.tx, &res);
+        res
+    }
+}
+
+fn record_batch(counters: &AtomicCounters, res: &io::Result<Vec<usize>>) {
+    match res {
+        Ok(lens) => lens.iter().for_each(|len| counters.add(*len)),
+        Err(e) => counters.record(&Err(io::Error::from(e.kind()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::pipe::PipeDevice;
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(64), 0);
+        assert_eq!(bucket(65), 1);
+        assert_eq!(bucket(1518), 5);
+        assert_eq!(bucket(9000), 6);
+    }
+    #[test]
+    fn test_counted() {
+        let (a, b) = PipeDevice::pair();
+        let a = Counted::new(a);
+        let b = Counted::new(b);
+        b.get_ref().set_nonblocking(true);
+        a.send(&[0u8; 60]).unwrap();
+        a.send_batch(&[&[0u8; 100], &[0u8; 1500]]).unwrap();
+        let mut buf = [0u8; 1514];
+        for _ in 0..3 {
+            b.recv(&mut buf).unwrap();
+        }
+        assert!(b.recv(&mut buf).is_err());
+
+        let tx = a.stats().tx;
+        assert_eq!((tx.packets, tx.bytes), (3, 1660));
+        assert_eq!(tx.histogram, [1, 1, 0, 0, 0, 1, 0]);
+        let rx = b.stats().rx;
+        assert_eq!((rx.packets, rx.bytes, rx.would_block, rx.errors), (3, 1660, 1, 0));
+
+        drop(a);
+        // the closed pipe reads as 0, which is not a packet
+        assert_eq!(b.recv(&mut buf).unwrap(), 0);
+        let rx = b.stats().rx;
+        assert_eq!((rx.packets, rx.empty, rx.histogram[0]), (3, 1, 1));
+        assert_eq!(b.send(&[0]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(b.reset().tx.errors, 1);
+        assert_eq!(b.stats(), Stats::default());
+    }
+}
@@ -3,37 +3,66 @@ use nix::fcntl;
 use nix::sys::stat;
 use nix::unistd::{read, write, close};
 use libc;
-use byteorder::{ByteOrder, NativeEndian};
+use byteorder::{BigEndian, ByteOrder, NativeEndian};
 use std::io;
 use std::os::unix::io::RawFd;
 use std::os::unix::io::AsRawFd;
-use crate::device::{Device, ifreq};
+use crate::device::Device;
+use crate::device::iface::{Interface, ifreq, IFNAMSIZ};
 use std::os::raw::{c_char, c_int};
 use std::borrow::BorrowMut;
 // use nix::sys::ioctl::ioctl;
 
 
-pub const IFNAMSIZ: usize = 16;
 
 
-pub const IFF_UP:      i16 = 0x1;
-
-pub const IFF_RUNNING: i16 = 0x40;
+pub use crate::device::iface::{IFF_UP, IFF_RUNNING};
 
 
 pub const IFF_TUN:   i16 = 0x0001;
 pub const IFF_TAP: i16 = 0x0002;
 
 pub const IFF_NO_PI: i16 = 0x1000;
+pub const IFF_VNET_HDR: i16 = 0x4000;
+pub const IFF_MULTI_QUEUE: i16 = 0x0100;
+pub const IFF_ATTACH_QUEUE: i16 = 0x0200;
+pub const IFF_DETACH_QUEUE: i16 = 0x0400;
+
+pub const TUN_PKT_STRIP: u16 = 0x0001;
+pub const PI_LENGTH: usize = 4;
+
+// TUNSETOFFLOAD flags
+pub const TUN_F_CSUM: u32 = 0x01;
+pub const TUN_F_TSO4: u32 = 0x02;
+pub const TUN_F_TSO6: u32 = 0x04;
+pub const TUN_F_TSO_ECN: u32 = 0x08;
+pub const TUN_F_UFO: u32 = 0x10;
+
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+pub const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+
+pub const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_UDP: u8 = 3;
+pub const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+pub const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+pub const VNET_HDR_LENGTH: usize = 10;
 
 nix::ioctl_write_ptr!(tunsetiff, b'T', 202, i32);
 nix::ioctl_write_ptr!(siocsifflags, b'T', 202, i32);
+nix::ioctl_write_ptr!(tunsetqueue, b'T', 217, i32);
+nix::ioctl_write_int!(tunsetpersist, b'T', 203);
+nix::ioctl_write_int!(tunsetowner, b'T', 204);
+nix::ioctl_write_int!(tunsetgroup, b'T', 206);
+nix::ioctl_write_int!(tunsetoffload, b'T', 208);
 
 #[derive(Debug)]
 pub struct TapDevice {
     fd: RawFd,
     ifreq: ifreq,
     mtu: usize,
+    flags: i16,
 }
 
 impl AsRawFd for TapDevice {
@@ -46,118 +75,606 @@ impl TapDevice {
     pub fn new(name: &mut str) -> io::Result<TapDevice> {
         Ok(TapDevice{
             fd: open_tap_device(name)?,
-            ifreq: ifreq_for(name),
+            ifreq: ifreq::new(name)?,
             mtu: 0,
+            flags: IFF_TAP|IFF_NO_PI,
         }
         )
     }
 
+    pub fn builder(name: &str) -> TapBuilder {
+        TapBuilder::new(name)
+    }
+
+    // attach to a tap created beforehand, e.g. a persistent one owned by this user
+    pub fn open_existing(name: &str) -> io::Result<TapDevice> {
+        TapBuilder::new(name).existing(true).open()
+    }
+
+    // open one queue per device on a single multi-queue interface
+    pub fn multi_queue(name: &str, queues: usize) -> io::Result<Vec<TapDevice>> {
+        let flags = IFF_TAP|IFF_NO_PI|IFF_MULTI_QUEUE;
+        (0..queues).map(|_| {
+            Ok(TapDevice {
+                fd: open_device(name, flags)?,
+                ifreq: ifreq::new(name)?,
+                mtu: 0,
+                flags,
+            })
+        }).collect()
+    }
+
+    pub fn is_multi_queue(&self) -> bool {
+        self.flags & IFF_MULTI_QUEUE != 0
+    }
+
+    // a detached queue stops receiving until it is attached again
+    pub fn attach_queue(&self) -> io::Result<()> {
+        self.set_queue(IFF_ATTACH_QUEUE)
+    }
+
+    pub fn detach_queue(&self) -> io::Result<()> {
+        self.set_queue(IFF_DETACH_QUEUE)
+    }
+
+    fn set_queue(&self, flags: i16) -> io::Result<()> {
+        if !self.is_multi_queue() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut req = ifreq::with_flags(&self.name(), flags)?;
+        unsafe { tunsetqueue(self.fd, &mut req as *mut _ as *mut _) }
+            .map_err(|_| io::Error::last_os_error())?;
+        Ok(())
+    }
+
     pub fn name(&self) -> String {
-        self.ifreq.ifr_name.iter().map(|&c| c as u8)
-            .filter(|&c| c != 0)
-            .map(|c| c as char)
-            .collect::<String>()
+        self.ifreq.name()
     }
 
-    pub fn up(&self) -> io::Result<()> {
-        let mut req = [0u8; 40];
-        let name = String::from_utf8(self.ifreq.ifr_name.iter().map(|&i| i as u8).collect::<Vec<u8>>()).unwrap();
-        req[..self.ifreq.ifr_name.len()].copy_from_slice(name.as_bytes());
-        NativeEndian::write_i16(&mut req[16..], IFF_UP|IFF_RUNNING);
-        let res = unsafe { libc::ioctl(self.fd, libc::SIOCSIFFLAGS, &mut req) };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
+    // a persistent interface outlives this device
+    pub fn set_persistent(&self, persistent: bool) -> io::Result<()> {
+        unsafe { tunsetpersist(self.fd, persistent as libc::c_ulong) }
+            .map_err(|_| io::Error::last_os_error())?;
+        Ok(())
+    }
+
+    // let a user without CAP_NET_ADMIN attach to the interface
+    pub fn set_owner(&self, uid: libc::uid_t) -> io::Result<()> {
+        unsafe { tunsetowner(self.fd, uid as libc::c_ulong) }
+            .map_err(|_| io::Error::last_os_error())?;
+        Ok(())
+    }
+
+    pub fn set_group(&self, gid: libc::gid_t) -> io::Result<()> {
+        unsafe { tunsetgroup(self.fd, gid as libc::c_ulong) }
+            .map_err(|_| io::Error::last_os_error())?;
+        Ok(())
+    }
+
+    pub fn has_vnet_hdr(&self) -> bool {
+        self.flags & IFF_VNET_HDR != 0
+    }
+
+    // TUN_F_* offloads the kernel may hand us unsegmented or without checksum
+    pub fn set_offload(&self, offload: u32) -> io::Result<()> {
+        if !self.has_vnet_hdr() {
+            return Err(io::ErrorKind::InvalidInput.into());
         }
+        unsafe { tunsetoffload(self.fd, offload as libc::c_ulong) }
+            .map_err(|_| io::Error::last_os_error())?;
         Ok(())
     }
 
+    // a frame with GSO may be far larger than the mtu, size buf for 64KiB
+    pub fn recv_vnet(&self, buf: &mut [u8]) -> io::Result<(VnetHeader, usize)> {
+        if !self.has_vnet_hdr() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut hdr = [0u8; VNET_HDR_LENGTH];
+        let len = read_with_header(self.fd, &mut hdr, buf)?;
+        Ok((VnetHeader::from_bytes(&hdr), len))
+    }
+
+    pub fn send_vnet(&self, hdr: VnetHeader, buf: &[u8]) -> io::Result<usize> {
+        if !self.has_vnet_hdr() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        write_with_header(self.fd, &hdr.to_bytes(), buf)
+    }
+
+    pub fn interface(&self) -> io::Result<Interface> {
+        Interface::new(&self.name())
+    }
+
+    pub fn up(&self) -> io::Result<()> {
+        self.interface()?.modify_flags(IFF_UP|IFF_RUNNING, 0)
+    }
+
     pub fn attach_interface(&mut self) -> io::Result<()> {
-        self.ifreq.ifr_flags = (IFF_TUN | IFF_NO_PI) as libc::c_int;
-        ifreq_ioctl(self.fd, &mut self.ifreq, 202).map(|_| ())
+        let name = self.name();
+        set_interface(self.fd, &name, self.flags)
     }
 
     pub fn interface_mtu(&mut self) -> io::Result<usize> {
-        let lower = unsafe {
-            let lower = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
-            if lower == -1 { return Err(io::Error::last_os_error()) }
-            lower
-        };
+        self.interface()?.mtu()
+    }
+}
+
+// options applied when a tap is opened, the defaults match TapDevice::new
+#[derive(Debug, Clone, Default)]
+pub struct TapBuilder {
+    name: String,
+    persistent: Option<bool>,
+    owner: Option<libc::uid_t>,
+    group: Option<libc::gid_t>,
+    existing: bool,
+    vnet_hdr: bool,
+}
 
-        let mtu = ifreq_ioctl(lower, &mut self.ifreq, 35105).map(|mtu| mtu as usize);
+impl TapBuilder {
+    pub fn new(name: &str) -> TapBuilder {
+        TapBuilder {
+            name: name.to_string(),
+            ..TapBuilder::default()
+        }
+    }
 
-        unsafe { libc::close(lower); }
+    pub fn persistent(mut self, persistent: bool) -> TapBuilder {
+        self.persistent = Some(persistent);
+        self
+    }
 
-        mtu
+    pub fn owner(mut self, uid: libc::uid_t) -> TapBuilder {
+        self.owner = Some(uid);
+        self
+    }
+
+    pub fn group(mut self, gid: libc::gid_t) -> TapBuilder {
+        self.group = Some(gid);
+        self
+    }
+
+    // fail with NotFound instead of creating the interface
+    pub fn existing(mut self, existing: bool) -> TapBuilder {
+        self.existing = existing;
+        self
+    }
+
+    // prepend a virtio_net_hdr to every frame, see TapDevice::recv_vnet
+    pub fn vnet_hdr(mut self, vnet_hdr: bool) -> TapBuilder {
+        self.vnet_hdr = vnet_hdr;
+        self
+    }
+
+    pub fn open(&self) -> io::Result<TapDevice> {
+        if self.existing {
+            Interface::new(&self.name)?.index().map_err(|e| match e.raw_os_error() {
+                Some(libc::ENODEV) => io::Error::new(io::ErrorKind::NotFound, e),
+                _ => e,
+            })?;
+        }
+        let flags = if self.vnet_hdr { IFF_TAP|IFF_NO_PI|IFF_VNET_HDR } else { IFF_TAP|IFF_NO_PI };
+        let dev = TapDevice {
+            fd: open_device(&self.name, flags)?,
+            ifreq: ifreq::new(&self.name)?,
+            mtu: 0,
+            flags,
+        };
+        if let Some(uid) = self.owner {
+            dev.set_owner(uid)?;
+        }
+        if let Some(gid) = self.group {
+            dev.set_group(gid)?;
+        }
+        if let Some(persistent) = self.persistent {
+            dev.set_persistent(persistent)?;
+        }
+        Ok(dev)
     }
 }
 
 impl Device for TapDevice {
+    // the vnet header is dropped, offloads should stay disabled for plain recv
     fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.has_vnet_hdr() {
+            return self.recv_vnet(buf).map(|(_, len)| len);
+        }
         let len = read(self.fd, buf)
             .map_err(|_| io::Error::last_os_error())?;
         Ok(len)
     }
 
     fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        if self.has_vnet_hdr() {
+            return self.send_vnet(VnetHeader::default(), buf);
+        }
         let len = write(self.fd, buf)
             .map_err(|_| io::Error::last_os_error())?;
         Ok(len)
     }
 }
 
+impl Drop for TapDevice {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+// packet information header prepended to each frame unless IFF_NO_PI is set
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct PacketInfo {
+    pub flags: u16,
+    pub proto: u16,
+}
+
+impl PacketInfo {
+    // guess the protocol from the ip version nibble
+    pub fn for_packet(buf: &[u8]) -> PacketInfo {
+        let proto = match buf.first().map(|b| b >> 4) {
+            Some(6) => 0x86dd,
+            _ => 0x0800,
+        };
+        PacketInfo { flags: 0, proto }
+    }
+
+    fn from_bytes(buf: &[u8; PI_LENGTH]) -> PacketInfo {
+        PacketInfo {
+            flags: NativeEndian::read_u16(&buf[0..2]),
+            proto: BigEndian::read_u16(&buf[2..4]),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; PI_LENGTH] {
+        let mut buf = [0u8; PI_LENGTH];
+        NativeEndian::write_u16(&mut buf[0..2], self.flags);
+        BigEndian::write_u16(&mut buf[2..4], self.proto);
+        buf
+    }
+
+    // the frame did not fit into the buffer
+    pub fn is_truncated(&self) -> bool {
+        self.flags & TUN_PKT_STRIP != 0
+    }
+}
+
+// struct virtio_net_hdr in the legacy native byte order the tap uses by default
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct VnetHeader {
+    pub flags: u8,
+    pub gso_type: u8,
+    // length of the headers copied into each segment
+    pub hdr_len: u16,
+    // payload bytes per segment
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VnetHeader {
+    // ask the kernel to fill in the checksum at csum_start + csum_offset
+    pub fn checksum(csum_start: u16, csum_offset: u16) -> VnetHeader {
+        VnetHeader {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            csum_start,
+            csum_offset,
+            ..VnetHeader::default()
+        }
+    }
+
+    pub fn needs_checksum(&self) -> bool {
+        self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0
+    }
+
+    pub fn is_gso(&self) -> bool {
+        self.gso_type & !VIRTIO_NET_HDR_GSO_ECN != VIRTIO_NET_HDR_GSO_NONE
+    }
+
+    fn from_bytes(buf: &[u8; VNET_HDR_LENGTH]) -> VnetHeader {
+        VnetHeader {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: NativeEndian::read_u16(&buf[2..4]),
+            gso_size: NativeEndian::read_u16(&buf[4..6]),
+            csum_start: NativeEndian::read_u16(&buf[6..8]),
+            csum_offset: NativeEndian::read_u16(&buf[8..10]),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; VNET_HDR_LENGTH] {
+        let mut buf = [0u8; VNET_HDR_LENGTH];
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        NativeEndian::write_u16(&mut buf[2..4], self.hdr_len);
+        NativeEndian::write_u16(&mut buf[4..6], self.gso_size);
+        NativeEndian::write_u16(&mut buf[6..8], self.csum_start);
+        NativeEndian::write_u16(&mut buf[8..10], self.csum_offset);
+        buf
+    }
+}
+
+// layer 3 device, carries raw ip packets without ethernet framing
+#[derive(Debug)]
+pub struct TunDevice {
+    fd: RawFd,
+    ifreq: ifreq,
+    packet_info: bool,
+}
+
+impl AsRawFd for TunDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl TunDevice {
+    pub fn new(name: &str) -> io::Result<TunDevice> {
+        Ok(TunDevice {
+            fd: open_device(name, IFF_TUN|IFF_NO_PI)?,
+            ifreq: ifreq::new(name)?,
+            packet_info: false,
+        })
+    }
+
+    // keep the 4 byte packet information header on every packet
+    pub fn with_packet_info(name: &str) -> io::Result<TunDevice> {
+        Ok(TunDevice {
+            fd: open_device(name, IFF_TUN)?,
+            ifreq: ifreq::new(name)?,
+            packet_info: true,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.ifreq.name()
+    }
+
+    pub fn has_packet_info(&self) -> bool {
+        self.packet_info
+    }
+
+    // the returned length excludes the packet information header
+    pub fn recv_with_info(&self, buf: &mut [u8]) -> io::Result<(PacketInfo, usize)> {
+        if !self.packet_info {
+            let len = read(self.fd, buf)
+                .map_err(|_| io::Error::last_os_error())?;
+            return Ok((PacketInfo::default(), len));
+        }
+        let mut pi = [0u8; PI_LENGTH];
+        let len = read_with_header(self.fd, &mut pi, buf)?;
+        Ok((PacketInfo::from_bytes(&pi), len))
+    }
+
+    pub fn send_with_info(&self, info: PacketInfo, buf: &[u8]) -> io::Result<usize> {
+        if !self.packet_info {
+            let len = write(self.fd, buf)
+                .map_err(|_| io::Error::last_os_error())?;
+            return Ok(len);
+        }
+        write_with_header(self.fd, &info.to_bytes(), buf)
+    }
+}
+
+impl Device for TunDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_with_info(buf).map(|(_, len)| len)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_with_info(PacketInfo::for_packet(buf), buf)
+    }
+}
+
+impl Drop for TunDevice {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+// read a frame preceded by a fixed size header, returns the frame length
+fn read_with_header(fd: RawFd, hdr: &mut [u8], buf: &mut [u8]) -> io::Result<usize> {
+    let iov = [
+        libc::iovec { iov_base: hdr.as_mut_ptr() as *mut libc::c_void, iov_len: hdr.len() },
+        libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() },
+    ];
+    let len = unsafe { libc::readv(fd, iov.as_ptr(), iov.len() as libc::c_int) };
+    if len == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if (len as usize) < hdr.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(len as usize - hdr.len())
+}
+
+fn write_with_header(fd: RawFd, hdr: &[u8], buf: &[u8]) -> io::Result<usize> {
+    let iov = [
+        libc::iovec { iov_base: hdr.as_ptr() as *mut libc::c_void, iov_len: hdr.len() },
+        libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() },
+    ];
+    let len = unsafe { libc::writev(fd, iov.as_ptr(), iov.len() as libc::c_int) };
+    if len == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((len as usize).saturating_sub(hdr.len()))
+}
+
 fn open_tap_device(name: &mut str) -> io::Result<RawFd> {
+    open_device(name, IFF_TAP|IFF_NO_PI)
+}
+
+fn open_device(name: &str, flags: i16) -> io::Result<RawFd> {
     let dev = "/dev/net/tun";
     let fd = match fcntl::open::<str>(dev, fcntl::OFlag::O_RDWR, stat::Mode::empty()) {
         Ok(fd) => Ok(fd),
         Err(_) => Err(io::Error::last_os_error())
     }?;
-    let mut req = [0u8; 40];
-    if name.len() > (IFNAMSIZ-1) {
-        return Err(io::ErrorKind::AddrNotAvailable.into());
+    if let Err(e) = set_interface(fd, name, flags) {
+        let _ = close(fd);
+        return Err(e);
     }
-    req[..name.len()].copy_from_slice(name.as_bytes());
-    NativeEndian::write_i16(&mut req[16..], IFF_TAP|IFF_NO_PI);
-    unsafe { tunsetiff(fd, &mut req as *mut _ as *mut _) }
-        .map_err(|_| io::Error::last_os_error() )?;
     Ok(fd)
 }
 
-fn ifreq_for(name: &str) -> ifreq {
-    let mut ifreq = ifreq {
-        ifr_name: [0; libc::IF_NAMESIZE],
-        ifr_flags: 0
-    };
-    for (i, byte) in name.as_bytes().iter().enumerate() {
-        ifreq.ifr_name[i] = *byte as libc::c_char
-    }
-    ifreq
-}
-
-fn ifreq_ioctl(lower: libc::c_int, ifreq: &mut ifreq,
-               cmd: libc::c_ulong) -> io::Result<libc::c_int> {
-    unsafe {
-        let res = libc::ioctl(lower, cmd as _, ifreq as *mut ifreq);
-        if res == -1 { return Err(io::Error::last_os_error()) }
+fn set_interface(fd: RawFd, name: &str, flags: i16) -> io::Result<()> {
+    if name.len() > (IFNAMSIZ-1) {
+        return Err(io::ErrorKind::AddrNotAvailable.into());
     }
-
-    Ok(ifreq.ifr_flags)
+    let mut req = ifreq::with_flags(name, flags)?;
+    unsafe { tunsetiff(fd, &mut req as *mut _ as *mut _) }
+        .map_err(|_| io::Error::last_os_error() )?;
+    Ok(())
 }
 
-
-
 #[cfg(test)]
 mod tests {
     use std::os::raw::c_int;
+    use std::net::{Ipv4Addr, UdpSocket};
     use pnet_datalink;
+    use crate::packet::ethernet::MACAddress;
+    use crate::packet::ip_protocol::IpProtocol;
+    use crate::packet::ipv4::{IpAddress, Packet};
+    use std::time::Duration;
+    use crate::util::netns::{readable, Namespace};
 
     #[test]
     fn test_open_tap_device() {
+        let ns = Namespace::new().unwrap();
         let mut name = String::from("test");
-        let mut dev = super::TapDevice::new(&mut name).unwrap();
+        let mut dev = ns.run(|| super::TapDevice::new(&mut name)).unwrap().unwrap();
         assert_ne!(dev.ifreq.ifr_name.iter().map(|&c| c as u8)
                        .map(|c| c as char)
                        .collect::<String>(), "test".to_string());
     }
+    #[test]
+    fn test_interface_attributes() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.tap("riface0").unwrap();
+        let iface = ns.run(|| dev.interface()).unwrap().unwrap();
+        let mac = MACAddress::new([0x02, 0x00, 0x5e, 0x10, 0x20, 0x30]);
+        iface.set_mac(mac).unwrap();
+        assert_eq!(iface.mac().unwrap(), mac);
+        iface.set_mtu(1400).unwrap();
+        assert_eq!(iface.mtu().unwrap(), 1400);
+        ns.run(|| dev.up()).unwrap().unwrap();
+        assert!(iface.is_up().unwrap());
+        iface.down().unwrap();
+        assert!(!iface.is_up().unwrap());
+    }
+    #[test]
+    fn test_persistent() {
+        let ns = Namespace::new().unwrap();
+        assert_eq!(ns.run(|| super::TapDevice::open_existing("rpers0")).unwrap().unwrap_err().kind(),
+                   std::io::ErrorKind::NotFound);
+        // ids mapped in the user namespace of an unprivileged run as well
+        let (uid, gid) = (nix::unistd::getuid().as_raw(), nix::unistd::getgid().as_raw());
+        let builder = super::TapDevice::builder("rpers0").persistent(true).owner(uid).group(gid);
+        drop(ns.run(|| builder.open()).unwrap().unwrap());
+        assert!(ns.netlink(|nl| nl.index("rpers0")).is_ok());
+
+        let dev = ns.run(|| super::TapDevice::open_existing("rpers0")).unwrap().unwrap();
+        dev.set_persistent(false).unwrap();
+        drop(dev);
+        assert!(ns.netlink(|nl| nl.index("rpers0")).is_err());
+    }
+    #[test]
+    fn test_vnet_hdr() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.run(|| super::TapDevice::builder("rvnet0").vnet_hdr(true).open()).unwrap().unwrap();
+        assert!(dev.has_vnet_hdr());
+        dev.set_offload(super::TUN_F_CSUM).unwrap();
+        ns.add_address("rvnet0", IpAddress::new(10, 202, 0, 1), 24).unwrap();
+        ns.set_up("rvnet0").unwrap();
+        let soc = ns.run(|| UdpSocket::bind((Ipv4Addr::new(10, 202, 0, 1), 0))).unwrap().unwrap();
+        soc.send_to(&[1, 2, 3, 4], (Ipv4Addr::new(10, 202, 0, 2), 9)).unwrap();
+
+        let peer = [0x02, 0x00, 0x5e, 0x00, 0x02, 0x02];
+        let mut buf = [0u8; 65536];
+        loop {
+            readable(&dev, Duration::from_secs(5)).unwrap();
+            let (hdr, len) = dev.recv_vnet(&mut buf).unwrap();
+            assert!(!hdr.is_gso());
+            match (&buf[12..14], buf[23]) {
+                // answer the arp request so the udp datagram goes out
+                ([0x08, 0x06], _) if buf[21] == 1 => {
+                    let mut reply = [0u8; 42];
+                    reply[0..6].copy_from_slice(&buf[6..12]);
+                    reply[6..12].copy_from_slice(&peer);
+                    reply[12..22].copy_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0x00, 6, 4, 0, 2]);
+                    reply[22..28].copy_from_slice(&peer);
+                    reply[28..32].copy_from_slice(&buf[38..42]);
+                    reply[32..38].copy_from_slice(&buf[6..12]);
+                    reply[38..42].copy_from_slice(&buf[28..32]);
+                    assert_eq!(dev.send_vnet(super::VnetHeader::default(), &reply).unwrap(), 42);
+                }
+                ([0x08, 0x00], 17) => {
+                    assert_eq!(&buf[0..6], &peer);
+                    assert_eq!(len, 14 + 20 + 8 + 4);
+                    // the checksum is left for us to fill in
+                    assert!(hdr.needs_checksum());
+                    assert_eq!((hdr.csum_start, hdr.csum_offset), (14 + 20, 6));
+                    break;
+                }
+                _ => continue,
+            }
+        }
+    }
+    #[test]
+    fn test_multi_queue() {
+        let ns = Namespace::new().unwrap();
+        let queues = ns.run(|| super::TapDevice::multi_queue("rmq0", 3)).unwrap().unwrap();
+        assert_eq!(queues.len(), 3);
+        assert!(queues.iter().all(|q| q.is_multi_queue() && q.name() == "rmq0"));
+        queues[1].detach_queue().unwrap();
+        queues[1].attach_queue().unwrap();
+    }
+    #[test]
+    fn test_single_queue_detach() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.tap("rsq0").unwrap();
+        assert!(!dev.is_multi_queue());
+        assert_eq!(dev.detach_queue().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+    #[test]
+    fn test_tun_recv_ipv4() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.run(|| super::TunDevice::new("rtun0")).unwrap().unwrap();
+        let (info, len) = recv_udp(&ns, &dev, IpAddress::new(10, 200, 0, 1));
+        assert_eq!(info, super::PacketInfo::default());
+        assert_eq!(len, 20 + 8 + 4);
+    }
+    #[test]
+    fn test_tun_packet_info() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.run(|| super::TunDevice::with_packet_info("rtun1")).unwrap().unwrap();
+        assert!(dev.has_packet_info());
+        let (info, len) = recv_udp(&ns, &dev, IpAddress::new(10, 201, 0, 1));
+        assert_eq!(info.proto, 0x0800);
+        assert!(!info.is_truncated());
+        assert_eq!(len, 20 + 8 + 4);
+    }
+
+    fn recv_udp(ns: &Namespace, dev: &super::TunDevice, addr: IpAddress) -> (super::PacketInfo, usize) {
+        let name = dev.name();
+        ns.add_address(&name, addr, 24).unwrap();
+        ns.set_up(&name).unwrap();
+        let a = addr.as_bytes();
+        let soc = ns.run(|| UdpSocket::bind((Ipv4Addr::new(a[0], a[1], a[2], a[3]), 0))).unwrap().unwrap();
+        soc.send_to(&[1, 2, 3, 4], (Ipv4Addr::new(a[0], a[1], a[2], 2), 9)).unwrap();
+        let mut buf = [0u8; 1500];
+        loop {
+            readable(dev, Duration::from_secs(5)).unwrap();
+            let (info, len) = dev.recv_with_info(&mut buf).unwrap();
+            // skip ipv6 neighbour discovery and friends
+            if buf[0] >> 4 != 4 {
+                continue;
+            }
+            let p = Packet::new(&buf[..len]).unwrap();
+            if p.protocol() == IpProtocol::UDP {
+                assert_eq!(p.source_addr(), addr);
+                return (info, len);
+            }
+        }
+    }
     // failed
     // #[test]
     // fn test_attach_interface() {
@@ -0,0 +1,92 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use crate::device::Device;
+
+// carries one ethernet frame per udp datagram, compatible with qemu's -netdev socket,udp=
+#[derive(Debug)]
+pub struct UdpTunnelDevice {
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+}
+
+impl AsRawFd for UdpTunnelDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl UdpTunnelDevice {
+    pub fn new<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, peer: B) -> io::Result<UdpTunnelDevice> {
+        let mut dev = UdpTunnelDevice::bind(local)?;
+        dev.set_peer(peer)?;
+        Ok(dev)
+    }
+
+    // receive only until a peer is set
+    pub fn bind<A: ToSocketAddrs>(local: A) -> io::Result<UdpTunnelDevice> {
+        Ok(UdpTunnelDevice {
+            socket: UdpSocket::bind(local)?,
+            peer: None,
+        })
+    }
+
+    pub fn set_peer<A: ToSocketAddrs>(&mut self, peer: A) -> io::Result<()> {
+        let peer = peer.to_socket_addrs()?
+            .next()
+            .ok_or(io::ErrorKind::InvalidInput)?;
+        self.peer = Some(peer);
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+}
+
+impl Device for UdpTunnelDevice {
+    // like qemu, datagrams are accepted from any source
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (len, _) = self.socket.recv_from(buf)?;
+        Ok(len)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let peer = self.peer.ok_or(io::ErrorKind::NotConnected)?;
+        self.socket.send_to(buf, peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_tunnel() {
+        let mut a = UdpTunnelDevice::bind("127.0.0.1:0").unwrap();
+        assert_eq!(a.send(&[0u8; 60]).unwrap_err().kind(), io::ErrorKind::NotConnected);
+        let b = UdpTunnelDevice::new("127.0.0.1:0", a.local_addr().unwrap()).unwrap();
+        a.set_peer(b.local_addr().unwrap()).unwrap();
+
+        let mut frame = [0u8; 60];
+        frame[0..6].copy_from_slice(&[0xff; 6]);
+        frame[12..14].copy_from_slice(&[0x08, 0x06]);
+        assert_eq!(a.send(&frame).unwrap(), 60);
+        let mut buf = [0u8; 1514];
+        assert_eq!(b.recv(&mut buf).unwrap(), 60);
+        assert_eq!(&buf[..60], &frame[..]);
+
+        assert_eq!(b.send(&[1, 2, 3]).unwrap(), 3);
+        assert_eq!(a.recv(&mut buf).unwrap(), 3);
+        a.set_nonblocking(true).unwrap();
+        assert_eq!(a.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
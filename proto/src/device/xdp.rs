@@ -0,0 +1,629 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use byteorder::{ByteOrder, NativeEndian};
+use nix::unistd::close;
+use crate::device::Device;
+use crate::device::iface::interface_index;
+use crate::util::netlink::{Netlink, XDP_FLAGS_SKB_MODE, XDP_FLAGS_UPDATE_IF_NOEXIST};
+
+pub const AF_XDP: libc::c_int = 44;
+pub const SOL_XDP: libc::c_int = 283;
+
+pub const XDP_MMAP_OFFSETS: libc::c_int = 1;
+pub const XDP_RX_RING: libc::c_int = 2;
+pub const XDP_TX_RING: libc::c_int = 3;
+pub const XDP_UMEM_REG: libc::c_int = 4;
+pub const XDP_UMEM_FILL_RING: libc::c_int = 5;
+pub const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+pub const XDP_PGOFF_RX_RING: libc::off_t = 0;
+pub const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+pub const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+pub const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+
+// sockaddr_xdp flags
+pub const XDP_COPY: u16 = 1 << 1;
+
+const DESC_LENGTH: usize = 16;
+const ADDR_LENGTH: usize = 8;
+
+mod bpf {
+    use std::io;
+    use std::os::unix::io::RawFd;
+    use byteorder::{ByteOrder, NativeEndian};
+
+    const BPF_MAP_CREATE: libc::c_long = 0;
+    const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+    const BPF_PROG_LOAD: libc::c_long = 5;
+
+    const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+    const BPF_PROG_TYPE_XDP: u32 = 6;
+
+    const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+    const BPF_PSEUDO_MAP_FD: u8 = 1;
+    const XDP_PASS: i32 = 2;
+
+    const ATTR_LENGTH: usize = 128;
+
+    fn syscall(cmd: libc::c_long, attr: &[u8; ATTR_LENGTH]) -> io::Result<RawFd> {
+        let res = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr.as_ptr(), ATTR_LENGTH as u32) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as RawFd)
+    }
+
+    // struct bpf_insn
+    fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        buf[0] = code;
+        buf[1] = (src << 4) | (dst & 0x0f);
+        NativeEndian::write_i16(&mut buf[2..4], off);
+        NativeEndian::write_i32(&mut buf[4..8], imm);
+        buf
+    }
+
+    pub fn create_xskmap(entries: u32) -> io::Result<RawFd> {
+        let mut attr = [0u8; ATTR_LENGTH];
+        NativeEndian::write_u32(&mut attr[0..4], BPF_MAP_TYPE_XSKMAP);
+        NativeEndian::write_u32(&mut attr[4..8], 4);
+        NativeEndian::write_u32(&mut attr[8..12], 4);
+        NativeEndian::write_u32(&mut attr[12..16], entries);
+        syscall(BPF_MAP_CREATE, &attr)
+    }
+
+    pub fn update(map: RawFd, key: u32, value: u32) -> io::Result<()> {
+        let mut attr = [0u8; ATTR_LENGTH];
+        NativeEndian::write_u32(&mut attr[0..4], map as u32);
+        NativeEndian::write_u64(&mut attr[8..16], &key as *const u32 as u64);
+        NativeEndian::write_u64(&mut attr[16..24], &value as *const u32 as u64);
+        syscall(BPF_MAP_UPDATE_ELEM, &attr).map(|_| ())
+    }
+
+    // return bpf_redirect_map(&xsks, ctx->rx_queue_index, XDP_PASS);
+    pub fn program(map: RawFd) -> Vec<u8> {
+        [
+            insn(0x61, 2, 1, 16, 0),                    // r2 = *(u32 *)(r1 + 16)
+            insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map),   // r1 = map
+            insn(0x00, 0, 0, 0, 0),
+            insn(0xb7, 3, 0, 0, XDP_PASS),              // r3 = XDP_PASS
+            insn(0x85, 0, 0, 0, BPF_FUNC_REDIRECT_MAP), // call bpf_redirect_map
+            insn(0x95, 0, 0, 0, 0),                     // exit
+        ].concat()
+    }
+
+    pub fn load_xdp(insns: &[u8]) -> io::Result<RawFd> {
+        let license = b"GPL\0";
+        let mut attr = [0u8; ATTR_LENGTH];
+        NativeEndian::write_u32(&mut attr[0..4], BPF_PROG_TYPE_XDP);
+        NativeEndian::write_u32(&mut attr[4..8], (insns.len() / 8) as u32);
+        NativeEndian::write_u64(&mut attr[8..16], insns.as_ptr() as u64);
+        NativeEndian::write_u64(&mut attr[16..24], license.as_ptr() as u64);
+        syscall(BPF_PROG_LOAD, &attr)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct xdp_umem_reg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct xdp_ring_offset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct xdp_mmap_offsets {
+    rx: xdp_ring_offset,
+    tx: xdp_ring_offset,
+    fr: xdp_ring_offset,
+    cr: xdp_ring_offset,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct sockaddr_xdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct XdpConfig {
+    pub queue_id: u32,
+    pub frame_nr: u32,
+    // power of two, at least 2048
+    pub frame_size: u32,
+    // entries of every ring, a power of two
+    pub ring_size: u32,
+    // load and attach the redirect program in generic mode
+    pub attach: bool,
+}
+
+impl Default for XdpConfig {
+    fn default() -> Self {
+        XdpConfig {
+            queue_id: 0,
+            frame_nr: 4096,
+            frame_size: 2048,
+            ring_size: 2048,
+            attach: true,
+        }
+    }
+}
+
+impl XdpConfig {
+    // bytes of umem for frame_nr frames, only a 32 bit usize can overflow
+    fn umem_len(&self) -> io::Result<usize> {
+        (self.frame_nr as usize).checked_mul(self.frame_size as usize)
+            .ok_or_else(|| io::ErrorKind::InvalidInput.into())
+    }
+}
+
+// a mmapped single producer single consumer ring
+#[derive(Debug)]
+struct Ring {
+    map: *mut u8,
+    map_len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *mut u8,
+    mask: u32,
+}
+
+impl Ring {
+    fn new(fd: RawFd, off: &xdp_ring_offset, pgoff: libc::off_t, entries: u32, entry: usize) -> io::Result<Ring> {
+        let map_len = off.desc as usize + entries as usize * entry;
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), map_len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE, fd, pgoff)
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let map = map as *mut u8;
+        unsafe {
+            Ok(Ring {
+                map,
+                map_len,
+                producer: map.add(off.producer as usize) as *const AtomicU32,
+                consumer: map.add(off.consumer as usize) as *const AtomicU32,
+                desc: map.add(off.desc as usize),
+                mask: entries - 1,
+            })
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    // entries ready for the consumer side
+    fn ready(&self) -> u32 {
+        self.producer().load(Ordering::Acquire).wrapping_sub(self.consumer().load(Ordering::Relaxed))
+    }
+
+    // free slots for the producer side
+    fn free(&self) -> u32 {
+        (self.mask + 1) - self.producer().load(Ordering::Relaxed).wrapping_sub(self.consumer().load(Ordering::Acquire))
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn entry(&self, index: u32, len: usize) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.desc.add((index & self.mask) as usize * len), len) }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.map_len); }
+    }
+}
+
+#[derive(Debug)]
+struct Rings {
+    rx: Ring,
+    tx: Ring,
+    fill: Ring,
+    completion: Ring,
+    // umem frames owned by user space and free for tx
+    free: Vec<u64>,
+}
+
+// AF_XDP socket bound to one queue of an interface
+#[derive(Debug)]
+pub struct XdpDevice {
+    fd: RawFd,
+    name: String,
+    config: XdpConfig,
+    umem: *mut u8,
+    umem_len: usize,
+    rings: Mutex<Rings>,
+    prog: Option<(RawFd, RawFd)>,
+}
+
+// the umem and rings are only touched with the rings lock held
+unsafe impl Send for XdpDevice {}
+unsafe impl Sync for XdpDevice {}
+
+impl AsRawFd for XdpDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl XdpDevice {
+    pub fn new(name: &str) -> io::Result<XdpDevice> {
+        XdpDevice::with_config(name, XdpConfig::default())
+    }
+
+    pub fn with_config(name: &str, config: XdpConfig) -> io::Result<XdpDevice> {
+        if !config.frame_size.is_power_of_two() || config.frame_size < 2048
+            || !config.ring_size.is_power_of_two() || config.frame_nr < 2 * config.ring_size {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let umem_len = config.umem_len()?;
+        let ifindex = interface_index(name)?;
+        let fd = unsafe {
+            let fd = libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0);
+            if fd == -1 { return Err(io::Error::last_os_error()) }
+            fd
+        };
+        let umem = unsafe {
+            libc::mmap(ptr::null_mut(), umem_len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        };
+        if umem == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            let _ = close(fd);
+            return Err(err);
+        }
+        let mut dev = XdpDevice {
+            fd,
+            name: name.to_string(),
+            config,
+            umem: umem as *mut u8,
+            umem_len,
+            rings: Mutex::new(setup_rings(fd, umem as *mut u8, umem_len, &config)
+                .inspect_err(|_| { unsafe { libc::munmap(umem, umem_len); } let _ = close(fd); })?),
+            prog: None,
+        };
+        dev.bind(ifindex)?;
+        if config.attach {
+            dev.attach()?;
+        }
+        Ok(dev)
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn queue_id(&self) -> u32 {
+        self.config.queue_id
+    }
+
+    fn bind(&self, ifindex: libc::c_int) -> io::Result<()> {
+        let addr = sockaddr_xdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: XDP_COPY,
+            sxdp_ifindex: ifindex as u32,
+            sxdp_queue_id: self.config.queue_id,
+            sxdp_shared_umem_fd: 0,
+        };
+        let res = unsafe {
+            libc::bind(self.fd, &addr as *const _ as *const libc::sockaddr,
+                       mem::size_of::<sockaddr_xdp>() as libc::socklen_t)
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // redirect every frame of our queue to this socket, in generic mode. the program
+    // serves one queue, so this fails with EBUSY if the interface already has one
+    fn attach(&mut self) -> io::Result<()> {
+        let map = bpf::create_xskmap(self.config.queue_id + 1)?;
+        let prog = bpf::load_xdp(&bpf::program(map))
+            .and_then(|prog| {
+                bpf::update(map, self.config.queue_id, self.fd as u32)
+                    .and_then(|_| Netlink::new()?.set_xdp(&self.name, prog, XDP_FLAGS_SKB_MODE | XDP_FLAGS_UPDATE_IF_NOEXIST).map_err(io::Error::from))
+                    .map(|_| prog)
+                    .inspect_err(|_| { let _ = close(prog); })
+            });
+        match prog {
+            Ok(prog) => {
+                self.prog = Some((prog, map));
+                Ok(())
+            }
+            Err(e) => {
+                let _ = close(map);
+                Err(e)
+            }
+        }
+    }
+
+    fn frame(&self, addr: u64, len: usize) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.umem.add(addr as usize), len) }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn frame_mut(&self, addr: u64, len: usize) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.umem.add(addr as usize), len) }
+    }
+
+    // move finished tx frames back to the free list
+    fn reclaim(&self, rings: &mut Rings) {
+        let n = rings.completion.ready();
+        let cons = rings.completion.consumer().load(Ordering::Relaxed);
+        for i in 0..n {
+            let addr = NativeEndian::read_u64(rings.completion.entry(cons.wrapping_add(i), ADDR_LENGTH));
+            rings.free.push(addr);
+        }
+        rings.completion.consumer().store(cons.wrapping_add(n), Ordering::Release);
+    }
+
+    fn kick(&self) -> io::Result<()> {
+        let res = unsafe { libc::sendto(self.fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+        if res == -1 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => {}
+                _ => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn wait(&self, events: libc::c_short) -> io::Result<()> {
+        let mut pfd = libc::pollfd { fd: self.fd, events, revents: 0 };
+        let res = unsafe { libc::poll(&mut pfd, 1, -1) };
+        if res == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Device for XdpDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let rings = self.rings.lock().unwrap();
+                if rings.rx.ready() > 0 {
+                    let cons = rings.rx.consumer().load(Ordering::Relaxed);
+                    let desc = rings.rx.entry(cons, DESC_LENGTH);
+                    let addr = NativeEndian::read_u64(&desc[0..8]);
+                    let len = NativeEndian::read_u32(&desc[8..12]) as usize;
+                    let copied = len.min(buf.len());
+                    buf[..copied].copy_from_slice(self.frame(addr, copied));
+                    rings.rx.consumer().store(cons.wrapping_add(1), Ordering::Release);
+
+                    // hand the frame back to the kernel for the next packet
+                    let frame = addr - addr % self.config.frame_size as u64;
+                    let prod = rings.fill.producer().load(Ordering::Relaxed);
+                    NativeEndian::write_u64(rings.fill.entry(prod, ADDR_LENGTH), frame);
+                    rings.fill.producer().store(prod.wrapping_add(1), Ordering::Release);
+                    return Ok(copied);
+                }
+            }
+            self.wait(libc::POLLIN)?;
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.config.frame_size as usize {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        loop {
+            {
+                let mut rings = self.rings.lock().unwrap();
+                self.reclaim(&mut rings);
+                if rings.tx.free() > 0 {
+                    if let Some(addr) = rings.free.pop() {
+                        self.frame_mut(addr, buf.len()).copy_from_slice(buf);
+                        let prod = rings.tx.producer().load(Ordering::Relaxed);
+                        let desc = rings.tx.entry(prod, DESC_LENGTH);
+                        NativeEndian::write_u64(&mut desc[0..8], addr);
+                        NativeEndian::write_u32(&mut desc[8..12], buf.len() as u32);
+                        NativeEndian::write_u32(&mut desc[12..16], 0);
+                        rings.tx.producer().store(prod.wrapping_add(1), Ordering::Release);
+                        drop(rings);
+                        // copy mode always needs a syscall to transmit
+                        self.kick()?;
+                        return Ok(buf.len());
+                    }
+                }
+            }
+            self.kick()?;
+            self.wait(libc::POLLOUT)?;
+        }
+    }
+}
+
+impl Drop for XdpDevice {
+    fn drop(&mut self) {
+        if let Some((prog, map)) = self.prog.take() {
+            if let Ok(nl) = Netlink::new() {
+                // leave a program someone attached after us in place
+                let _ = nl.clear_xdp(&self.name, prog, XDP_FLAGS_SKB_MODE);
+            }
+            let _ = close(prog);
+            let _ = close(map);
+        }
+        let _ = close(self.fd);
+        unsafe { libc::munmap(self.umem as *mut libc::c_void, self.umem_len); }
+    }
+}
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, val: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(fd, SOL_XDP, opt, val as *const T as *const libc::c_void,
+                         mem::size_of::<T>() as libc::socklen_t)
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn setup_rings(fd: RawFd, umem: *mut u8, umem_len: usize, config: &XdpConfig) -> io::Result<Rings> {
+    let reg = xdp_umem_reg {
+        addr: umem as u64,
+        len: umem_len as u64,
+        chunk_size: config.frame_size,
+        ..Default::default()
+    };
+    setsockopt(fd, XDP_UMEM_REG, &reg)?;
+    for opt in [XDP_UMEM_FILL_RING, XDP_UMEM_COMPLETION_RING, XDP_RX_RING, XDP_TX_RING].iter() {
+        setsockopt(fd, *opt, &config.ring_size)?;
+    }
+
+    let mut off = xdp_mmap_offsets::default();
+    let mut len = mem::size_of::<xdp_mmap_offsets>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, SOL_XDP, XDP_MMAP_OFFSETS, &mut off as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if (len as usize) < mem::size_of::<xdp_mmap_offsets>() {
+        // kernels before 5.4 lack the flags word
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+
+    let entries = config.ring_size;
+    let mut rings = Rings {
+        rx: Ring::new(fd, &off.rx, XDP_PGOFF_RX_RING, entries, DESC_LENGTH)?,
+        tx: Ring::new(fd, &off.tx, XDP_PGOFF_TX_RING, entries, DESC_LENGTH)?,
+        fill: Ring::new(fd, &off.fr, XDP_UMEM_PGOFF_FILL_RING, entries, ADDR_LENGTH)?,
+        completion: Ring::new(fd, &off.cr, XDP_UMEM_PGOFF_COMPLETION_RING, entries, ADDR_LENGTH)?,
+        free: Vec::new(),
+    };
+
+    // the first ring_size frames feed rx, the rest are for tx
+    let frame_size = config.frame_size as u64;
+    for i in 0..entries {
+        NativeEndian::write_u64(rings.fill.entry(i, ADDR_LENGTH), i as u64 * frame_size);
+    }
+    rings.fill.producer().store(entries, Ordering::Release);
+    rings.free = (entries as u64..config.frame_nr as u64).map(|i| i * frame_size).collect();
+    Ok(rings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::util::netns::{readable, Namespace};
+
+    // loading a program needs CAP_BPF in the initial user namespace, which the user
+    // namespace of an unprivileged run does not give
+    fn bpf_allowed() -> bool {
+        match bpf::create_xskmap(1) {
+            Ok(map) => {
+                let _ = close(map);
+                true
+            }
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => {
+                eprintln!("[warn] skipped, loading xdp programs is not permitted");
+                false
+            }
+            Err(e) => panic!("failed to create a map: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_program() {
+        let prog = bpf::program(3);
+        assert_eq!(prog.len(), 6 * 8);
+        // ld_imm64 carries the map fd with the pseudo map source register
+        assert_eq!(&prog[8..10], &[0x18, 0x11]);
+        assert_eq!(NativeEndian::read_i32(&prog[12..16]), 3);
+        assert_eq!(prog[40], 0x95);
+    }
+    #[test]
+    fn test_invalid_config() {
+        let config = XdpConfig { frame_size: 3000, ..XdpConfig::default() };
+        assert_eq!(XdpDevice::with_config("lo", config).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+    #[test]
+    fn test_umem_len() {
+        // 4 GiB, one byte more than a 32 bit usize holds
+        let config = XdpConfig { frame_nr: 1 << 20, frame_size: 1 << 12, ..XdpConfig::default() };
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(config.umem_len().unwrap() as u64, 1u64 << 32);
+        } else {
+            assert_eq!(config.umem_len().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+    #[test]
+    fn test_attach_busy() {
+        let ns = Namespace::new().unwrap();
+        if !bpf_allowed() {
+            return;
+        }
+        ns.run(|| {
+            let map = bpf::create_xskmap(1).unwrap();
+            let prog = bpf::load_xdp(&bpf::program(map)).unwrap();
+            let nl = Netlink::new().unwrap();
+            nl.set_xdp("lo", prog, XDP_FLAGS_SKB_MODE).unwrap();
+            let err = XdpDevice::new("lo").unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+            // the foreign program is still attached, so replacing it as expected works
+            nl.clear_xdp("lo", prog, XDP_FLAGS_SKB_MODE).unwrap();
+            let _ = close(prog);
+            let _ = close(map);
+        }).unwrap();
+    }
+    #[test]
+    fn test_xdp_send_recv() {
+        let ns = Namespace::new().unwrap();
+        if !bpf_allowed() {
+            return;
+        }
+        // the device detaches its program by name on drop, so it has to stay inside
+        ns.run(|| {
+            let dev = XdpDevice::new("lo").unwrap();
+            let mut frame = [0u8; 60];
+            frame[12..14].copy_from_slice(&[0x88, 0xb8]);
+            frame[14..18].copy_from_slice(&[0x0d, 0xf0, 0xad, 0x8b]);
+            assert_eq!(dev.send(&frame).unwrap(), 60);
+            let mut buf = [0u8; 2048];
+            loop {
+                readable(&dev, Duration::from_secs(5)).unwrap();
+                let len = dev.recv(&mut buf).unwrap();
+                if buf[12..18] == frame[12..18] {
+                    assert_eq!(len, 60);
+                    break;
+                }
+            }
+        }).unwrap();
+    }
+}
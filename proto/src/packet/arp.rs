@@ -1,7 +1,7 @@
 
 use super::ethernet::EtherType as ProtocolType;
 use byteorder::{BigEndian, ByteOrder};
-use crate::packet::error::{Error, ErrorKind};
+use crate::packet::error::{self, Error};
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
 pub enum HardwareType {
@@ -64,8 +64,19 @@ impl std::convert::From<Operation> for u16 {
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
-pub struct Packet {
-    buffer: Vec<u8>
+pub struct Packet<T: AsRef<[u8]>> {
+    buffer: T
+}
+
+impl Packet<Vec<u8>> {
+    pub fn with_type(htype: HardwareType, ptype: ProtocolType) -> Result<Self, Error> {
+        let mut buf = vec![0u8; field::OPER.end+ 2*(htype.addr_len()+ptype.addr_len())];
+        buf[field::HLEN] = htype.addr_len() as u8;
+        buf[field::PLEN] = ptype.addr_len() as u8;
+        BigEndian::write_u16(&mut buf[field::HTYPE], htype.into());
+        BigEndian::write_u16(&mut buf[field::PTYPE], ptype.into());
+        Packet::new(buf)
+    }
 }
 
 mod field {
@@ -99,115 +110,104 @@ mod field {
     }
 }
 
-impl Packet {
-    pub fn new(buffer: Vec<u8>) -> Result<Self, Error> {
+impl<T: AsRef<[u8]>> Packet<T> {
+    pub fn new(buffer: T) -> Result<Self, Error> {
         let p = Packet { buffer };
         p.is_valid()?;
         Ok(p)
     }
 
     pub fn len(&self) -> usize {
-        self.buffer.len()
+        self.buffer.as_ref().len()
     }
 
     fn is_valid(&self) -> Result<(), Error> {
-        let l = self.len();
-        if l < field::OPER.end {
-            return Err(Error::from(ErrorKind::InvalidFormat))
-        } else if l < field::TPA(self.hlen(), self.plen()).end {
-            return Err(Error::from(ErrorKind::InvalidFormat))
-        }
-        Ok(())
+        let buf = self.buffer.as_ref();
+        error::check_len(buf, field::OPER.end)?;
+        error::check_len(buf, field::TPA(self.hlen(), self.plen()).end)
     }
 
-    pub fn into_buffer(self) -> Vec<u8> {
+    pub fn into_buffer(self) -> T {
         self.buffer
     }
 
     pub fn htype(&self) -> HardwareType {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         let typ = BigEndian::read_u16(&buf[field::HTYPE]);
         HardwareType::from(typ)
     }
 
     pub fn ptype(&self) -> ProtocolType {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         let typ = BigEndian::read_u16(&buf[field::PTYPE]);
         ProtocolType::from(typ)
     }
 
     pub fn hlen(&self) -> usize {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         buf[field::HLEN] as usize
     }
 
     pub fn plen(&self) -> usize {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         buf[field::PLEN] as usize
     }
 
     pub fn operation(&self) -> Operation {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         let op = BigEndian::read_u16(&buf[field::OPER]);
         Operation::from(op)
     }
 
     pub fn source_hardware_addr(&self) -> &[u8] {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         &buf[field::SHA(self.hlen(), self.plen())]
     }
 
     pub fn source_protocol_addr(&self) -> &[u8] {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         &buf[field::SPA(self.hlen(), self.plen())]
     }
 
     pub fn target_hardware_addr(&self) -> &[u8] {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         &buf[field::THA(self.hlen(), self.plen())]
     }
 
     pub fn target_protocol_addr(&self) -> &[u8] {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         &buf[field::TPA(self.hlen(), self.plen())]
     }
+}
 
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Packet<T> {
     // setter
-    pub fn with_type(htype: HardwareType, ptype: ProtocolType) -> Result<Self, Error> {
-        let mut buf = vec![0u8; field::OPER.end+ 2*(htype.addr_len()+ptype.addr_len())];
-        buf[field::HLEN] = htype.addr_len() as u8;
-        buf[field::PLEN] = ptype.addr_len() as u8;
-        BigEndian::write_u16(&mut buf[field::HTYPE], htype.into());
-        BigEndian::write_u16(&mut buf[field::PTYPE], ptype.into());
-        Packet::new(buf)
-    }
-
     pub fn set_op(&mut self, op: Operation) {
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         BigEndian::write_u16(&mut buf[field::OPER], op.into())
     }
 
     pub fn set_source_hardware_addr(&mut self, src: &[u8]) {
         let (hlen, plen) = (self.hlen(), self.plen());
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         buf[field::SHA(hlen, plen)].copy_from_slice(src)
     }
 
     pub fn set_source_protocol_addr(&mut self, src: &[u8]) {
         let (hlen, plen) = (self.hlen(), self.plen());
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         buf[field::SPA(hlen, plen)].copy_from_slice(src)
     }
 
     pub fn set_target_hardware_addr(&mut self, target: &[u8]) {
         let (hlen, plen) = (self.hlen(), self.plen());
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         buf[field::THA(hlen, plen)].copy_from_slice(target)
     }
 
     pub fn set_target_protocol_addr(&mut self, target: &[u8]) {
         let (hlen, plen) = (self.hlen(), self.plen());
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         buf[field::TPA(hlen, plen)].copy_from_slice(target)
     }
 }
@@ -215,8 +215,10 @@ impl Packet {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use nix::unistd::SysconfVar::OPEN_MAX;
     use crate::packet::ethernet::MACAddress;
+    use crate::packet::error::ErrorKind;
 
     static PACKET_BYTES: [u8; 28] =
         [0x00, 0x01,
@@ -245,6 +247,14 @@ mod tests {
         assert_eq!(p.source_hardware_addr(), &[0x11, 0x12, 0x13, 0x14, 0x15, 0x16])
     }
     #[test]
+    fn test_truncated() {
+        let err = Packet::new(&PACKET_BYTES[..7]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Truncated { needed: 8, got: 7 });
+        // the address lengths come from the header itself
+        let err = Packet::new(&PACKET_BYTES[..27]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Truncated { needed: 28, got: 27 });
+    }
+    #[test]
     fn test_with_type() {
         let p = Packet::with_type(HardwareType::Ethernet, ProtocolType::Ipv4).unwrap();
         assert_eq!(p.hlen(), 6);
@@ -264,4 +274,33 @@ mod tests {
         assert_eq!(p.source_protocol_addr(), &[0,0,0,0]);
         assert_eq!(p.source_hardware_addr(), MACAddress::BROADCAST.as_bytes())
     }
-}
\ No newline at end of file
+
+    proptest! {
+        #[test]
+        fn test_round_trip(op in 1u16..=2, sha: [u8; 6], spa: [u8; 4], tha: [u8; 6], tpa: [u8; 4]) {
+            let mut p = Packet::with_type(HardwareType::Ethernet, ProtocolType::Ipv4).unwrap();
+            p.set_op(Operation::from(op));
+            p.set_source_hardware_addr(&sha);
+            p.set_source_protocol_addr(&spa);
+            p.set_target_hardware_addr(&tha);
+            p.set_target_protocol_addr(&tpa);
+            let buf = p.into_buffer();
+            let p = Packet::new(&buf[..]).unwrap();
+            prop_assert_eq!(p.htype(), HardwareType::Ethernet);
+            prop_assert_eq!(p.ptype(), ProtocolType::Ipv4);
+            prop_assert_eq!(p.operation(), Operation::from(op));
+            prop_assert_eq!(p.source_hardware_addr(), &sha[..]);
+            prop_assert_eq!(p.source_protocol_addr(), &spa[..]);
+            prop_assert_eq!(p.target_hardware_addr(), &tha[..]);
+            prop_assert_eq!(p.target_protocol_addr(), &tpa[..]);
+        }
+        #[test]
+        fn test_parse_any(data in prop::collection::vec(any::<u8>(), 0..64)) {
+            if let Ok(p) = Packet::new(&data[..]) {
+                let end = 8 + 2 * (p.hlen() + p.plen());
+                prop_assert!(end <= data.len());
+                prop_assert_eq!(p.target_protocol_addr().len(), p.plen());
+            }
+        }
+    }
+}
@@ -3,8 +3,17 @@ use std::fmt;
 
 #[derive(Debug, Error, Eq, PartialEq, Copy, Clone)]
 pub enum ErrorKind {
-    InvalidFormat,
-    Checksum,
+    #[error("truncated packet: need {needed} bytes, got {got}")]
+    Truncated { needed: usize, got: usize },
+    #[error("bad header length")]
+    BadHeaderLength,
+    // a length field smaller than the header it covers
+    #[error("bad total length")]
+    BadLength,
+    #[error("bad version")]
+    BadVersion,
+    #[error("bad checksum: expected {expected:#06x}, got {got:#06x}")]
+    BadChecksum { expected: u16, got: u16 },
 }
 
 #[derive(Debug, Error, Eq, PartialEq, Copy, Clone)]
@@ -12,31 +21,15 @@ pub struct Error {
     inner: ErrorKind
 }
 
-// impl Error {
-//     /// Return true if the underlying error has the same type as T.
-//     // pub fn is<T: error::Error + 'static>(&self) -> bool {
-//     //     self.get_ref().is::<T>()
-//     // }
-//
-//     /// Return a reference to the lower level, inner error.
-//     // pub fn get_ref(&self) -> &(dyn error::Error + 'static) {
-//     //     use self::ErrorKind::*;
-//     //
-//     //     match self.inner {
-//     //         InvalidFormat
-//     //     }
-//     // }
-// }
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(&self.inner, f)
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        self.inner
     }
 }
 
-impl fmt::Display for ErrorKind {
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("packet error kind")
+        fmt::Display::fmt(&self.inner, f)
     }
 }
 
@@ -46,4 +39,26 @@ impl std::convert::From<ErrorKind> for Error {
             inner: kind
         }
     }
-}
\ No newline at end of file
+}
+
+// fails with Truncated unless buf holds at least needed bytes
+pub(crate) fn check_len(buf: &[u8], needed: usize) -> Result<(), Error> {
+    if buf.len() < needed {
+        return Err(Error::from(ErrorKind::Truncated { needed, got: buf.len() }));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let e = Error::from(ErrorKind::Truncated { needed: 14, got: 3 });
+        assert_eq!(e.to_string(), "truncated packet: need 14 bytes, got 3");
+        let e = Error::from(ErrorKind::BadChecksum { expected: 0x0821, got: 0 });
+        assert_eq!(e.to_string(), "bad checksum: expected 0x0821, got 0x0000");
+        assert_eq!(e.kind(), ErrorKind::BadChecksum { expected: 0x0821, got: 0 });
+    }
+}
@@ -1,14 +1,14 @@
 use std::fmt;
-use {Result};
 use byteorder::{BigEndian, ByteOrder};
 use std::fmt::Display;
+use crate::packet::error::{self, Error};
 
 pub const HEADER_LENGTH: usize = 14;
 
-// ethernet frame
+// ethernet frame over any byte buffer, e.g. Vec<u8>, &[u8] or &mut [u8]
 #[derive(Debug)]
-pub struct Frame {
-    buffer: Vec<u8>
+pub struct Frame<T: AsRef<[u8]>> {
+    buffer: T
 }
 
 mod field {
@@ -25,96 +25,114 @@ mod field {
 //     }
 // }
 
-impl Frame {
-    pub fn new(buffer: Vec<u8>) -> Self {
-        Frame {
-            buffer
-        }
-    }
-
+impl Frame<Vec<u8>> {
     pub fn from_body(body: &[u8]) -> Self {
         let length = HEADER_LENGTH + body.len();
         let mut buf = vec![0u8; length];
         buf[field::PAYLOAD].copy_from_slice(body);
-        Frame::new(buf)
+        Frame { buffer: buf }
+    }
+}
+
+impl<T: AsRef<[u8]>> Frame<T> {
+    pub fn new(buffer: T) -> Result<Self, Error> {
+        error::check_len(buffer.as_ref(), HEADER_LENGTH)?;
+        Ok(Frame {
+            buffer
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
     }
 
     // getter
     pub fn dst(&self) -> MACAddress {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         MACAddress::from_bytes(&buf[field::DST])
     }
 
     pub fn src(&self) -> MACAddress {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         MACAddress::from_bytes(&buf[field::SRC])
     }
 
     pub fn ethertype(&self) -> EtherType {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         let typ = BigEndian::read_u16(&buf[field::TYP]);
-        match typ {
-            0x0800 => EtherType::Ipv4,
-            0x0806 => EtherType::Ipv6,
-            0x86dd => EtherType::Arp,
-            _ => EtherType::UNKNOWN
-        }
+        EtherType::from(typ)
     }
 
     pub fn payload(&self) -> &[u8] {
-        let buf = self.buffer.as_slice();
+        let buf = self.buffer.as_ref();
         &buf[field::PAYLOAD]
     }
 
+    // fotmatter
+    pub fn log(&self) {
+        println!("dst={:?}", self.dst());
+        println!("src={:?}", self.src());
+        println!("typ={:?}", self.ethertype());
+        // println!("{:?}", self.payload());
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Frame<T> {
     pub fn mut_payload(&mut self) -> &mut [u8] {
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         &mut buf[field::PAYLOAD]
     }
 
     // setter
     pub fn set_dst(&mut self, dst: MACAddress) {
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         buf[field::DST].copy_from_slice(dst.as_bytes())
     }
 
     pub fn set_src(&mut self, src: MACAddress) {
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         buf[field::SRC].copy_from_slice(src.as_bytes())
     }
 
     pub fn set_type(&mut self, typ: EtherType) {
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         BigEndian::write_u16(&mut buf[field::TYP], typ.into())
     }
 
     pub fn set_payload(&mut self, payload: &[u8]) {
-        let buf = self.buffer.as_mut_slice();
+        let buf = self.buffer.as_mut();
         buf[field::PAYLOAD].copy_from_slice(payload)
     }
-
-    // fotmatter
-    pub fn log(&self) {
-        println!("dst={:?}", self.dst());
-        println!("src={:?}", self.src());
-        println!("typ={:?}", self.ethertype());
-        // println!("{:?}", self.payload());
-    }
 }
 
 // ether type definition
-#[derive(Eq, PartialEq)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
 pub enum EtherType {
-    Ipv4 = 0x0800,
-    Ipv6 = 0x0806,
-    Arp = 0x86dd,
-    UNKNOWN,
+    Ipv4,
+    Arp,
+    Ipv6,
+    // 802.1Q customer tag
+    Vlan,
+    // 802.1ad service tag
+    QinQ,
+    MplsUnicast,
+    MplsMulticast,
+    Lldp,
+    PppoeDiscovery,
+    PppoeSession,
+    // slow protocols, LACP and marker
+    Slow,
+    Macsec,
+    Ptp,
+    // kept as is so that it serializes back to the same value
+    Unknown(u16),
 }
 
 impl EtherType {
     pub fn addr_len(&self) -> usize {
         match self {
             &EtherType::Ipv4 => 4,
-            &EtherType::Ipv6 => 8,
+            &EtherType::Ipv6 => 16,
             _ => 0,
         }
     }
@@ -124,9 +142,19 @@ impl fmt::Debug for EtherType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             &EtherType::Ipv4 => write!(f, "IPv4"),
-            &EtherType::Ipv6 => write!(f, "IPv6"),
             &EtherType::Arp => write!(f, "ARP"),
-            &EtherType::UNKNOWN => write!(f, "UNKNOWN")
+            &EtherType::Ipv6 => write!(f, "IPv6"),
+            &EtherType::Vlan => write!(f, "802.1Q"),
+            &EtherType::QinQ => write!(f, "802.1ad"),
+            &EtherType::MplsUnicast => write!(f, "MPLS"),
+            &EtherType::MplsMulticast => write!(f, "MPLS multicast"),
+            &EtherType::Lldp => write!(f, "LLDP"),
+            &EtherType::PppoeDiscovery => write!(f, "PPPoE discovery"),
+            &EtherType::PppoeSession => write!(f, "PPPoE session"),
+            &EtherType::Slow => write!(f, "slow protocols"),
+            &EtherType::Macsec => write!(f, "MACsec"),
+            &EtherType::Ptp => write!(f, "PTP"),
+            &EtherType::Unknown(typ) => write!(f, "UNKNOWN({:#06x})", typ),
         }
     }
 }
@@ -135,9 +163,19 @@ impl std::convert::From<u16> for EtherType {
     fn from(typ: u16) -> Self {
         match typ {
             0x0800 => EtherType::Ipv4,
-            0x0806 => EtherType::Ipv6,
-            0x86dd => EtherType::Arp,
-            _ => EtherType::UNKNOWN
+            0x0806 => EtherType::Arp,
+            0x86dd => EtherType::Ipv6,
+            0x8100 => EtherType::Vlan,
+            0x88a8 => EtherType::QinQ,
+            0x8847 => EtherType::MplsUnicast,
+            0x8848 => EtherType::MplsMulticast,
+            0x88cc => EtherType::Lldp,
+            0x8863 => EtherType::PppoeDiscovery,
+            0x8864 => EtherType::PppoeSession,
+            0x8809 => EtherType::Slow,
+            0x88e5 => EtherType::Macsec,
+            0x88f7 => EtherType::Ptp,
+            _ => EtherType::Unknown(typ)
         }
     }
 }
@@ -146,9 +184,19 @@ impl std::convert::From<EtherType> for u16 {
     fn from(typ: EtherType) -> Self {
         match typ {
             EtherType::Ipv4 => 0x0800,
-            EtherType::Ipv6 => 0x0806,
-            EtherType::Arp => 0x86dd,
-            EtherType::UNKNOWN => 0x0000
+            EtherType::Arp => 0x0806,
+            EtherType::Ipv6 => 0x86dd,
+            EtherType::Vlan => 0x8100,
+            EtherType::QinQ => 0x88a8,
+            EtherType::MplsUnicast => 0x8847,
+            EtherType::MplsMulticast => 0x8848,
+            EtherType::Lldp => 0x88cc,
+            EtherType::PppoeDiscovery => 0x8863,
+            EtherType::PppoeSession => 0x8864,
+            EtherType::Slow => 0x8809,
+            EtherType::Macsec => 0x88e5,
+            EtherType::Ptp => 0x88f7,
+            EtherType::Unknown(typ) => typ,
         }
     }
 }
@@ -189,6 +237,7 @@ impl fmt::Debug for MACAddress {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use crate::packet::ethernet::field::PAYLOAD;
 
     static FRAME_BYTES: [u8; 64] =
@@ -214,32 +263,72 @@ mod tests {
 
     #[test]
     fn test_set_dst() {
-        let mut frame = Frame::new(FRAME_BYTES.to_vec());
+        let mut frame = Frame::new(FRAME_BYTES.to_vec()).unwrap();
         frame.set_dst(MACAddress::new(MACAddress::BROADCAST.0));
         assert_eq!(MACAddress::BROADCAST, frame.dst());
     }
     #[test]
     fn test_set_src() {
-        let mut frame = Frame::new(FRAME_BYTES.to_vec());
+        let mut frame = Frame::new(FRAME_BYTES.to_vec()).unwrap();
         frame.set_src(MACAddress::new(MACAddress::BROADCAST.0));
         assert_eq!(MACAddress::BROADCAST, frame.src());
     }
     #[test]
     fn test_set_type() {
-        let mut frame = Frame::new(FRAME_BYTES.to_vec());
+        let mut frame = Frame::new(FRAME_BYTES.to_vec()).unwrap();
         frame.set_type(EtherType::Arp);
         assert_eq!(EtherType::Arp, frame.ethertype());
     }
     #[test]
+    fn test_ethertype() {
+        assert_eq!(EtherType::from(0x0806), EtherType::Arp);
+        assert_eq!(EtherType::from(0x86dd), EtherType::Ipv6);
+        assert_eq!(EtherType::Ipv6.addr_len(), 16);
+        assert_eq!(u16::from(EtherType::QinQ), 0x88a8);
+        let mut frame = Frame::new(FRAME_BYTES.to_vec()).unwrap();
+        frame.set_type(EtherType::from(0x9000));
+        assert_eq!(frame.ethertype(), EtherType::Unknown(0x9000));
+        assert_eq!(&frame.into_inner()[12..14], &[0x90, 0x00]);
+    }
+    #[test]
     fn test_set_payload() {
-        let mut frame = Frame::new(FRAME_BYTES.to_vec());
+        let mut frame = Frame::new(FRAME_BYTES.to_vec()).unwrap();
         frame.set_payload(&PAYLOAD_BYTES);
         assert_eq!(frame.payload(), PAYLOAD_BYTES.as_ref());
     }
     #[test]
+    fn test_truncated() {
+        let err = Frame::new(&FRAME_BYTES[..13]).unwrap_err();
+        assert_eq!(err.kind(), error::ErrorKind::Truncated { needed: 14, got: 13 });
+        assert!(Frame::new(&FRAME_BYTES[..14]).unwrap().payload().is_empty());
+    }
+    #[test]
     fn test_from_body() {
         let frame = Frame::from_body(&PAYLOAD_BYTES);
         assert_eq!(frame.payload(), PAYLOAD_BYTES.as_ref());
         assert_eq!(frame.dst(), MACAddress::new([0,0,0,0,0,0]));
     }
-}
\ No newline at end of file
+
+    proptest! {
+        #[test]
+        fn test_round_trip(dst: [u8; 6], src: [u8; 6], typ: u16,
+                           body in prop::collection::vec(any::<u8>(), 0..1500)) {
+            let mut frame = Frame::from_body(&body);
+            frame.set_dst(MACAddress::new(dst));
+            frame.set_src(MACAddress::new(src));
+            frame.set_type(EtherType::from(typ));
+            let buf = frame.into_inner();
+            let frame = Frame::new(&buf[..]).unwrap();
+            prop_assert_eq!(frame.dst(), MACAddress::new(dst));
+            prop_assert_eq!(frame.src(), MACAddress::new(src));
+            prop_assert_eq!(u16::from(frame.ethertype()), typ);
+            prop_assert_eq!(frame.payload(), &body[..]);
+        }
+        #[test]
+        fn test_parse_any(data in prop::collection::vec(any::<u8>(), 0..64)) {
+            if let Ok(frame) = Frame::new(&data[..]) {
+                prop_assert_eq!(frame.payload().len(), data.len() - HEADER_LENGTH);
+            }
+        }
+    }
+}
@@ -2,7 +2,7 @@ use crate::packet::icmp::Type::EchoRequest;
 use std::fmt;
 use std::intrinsics::write_bytes;
 use std::fmt::Debug;
-use crate::packet::error::Error;
+use crate::packet::error::{self, Error, ErrorKind};
 use byteorder::{BigEndian, ByteOrder};
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
@@ -134,8 +134,8 @@ impl std::convert::From<DstUnreachableCode> for u8 {
 }
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq)]
-pub struct Packet {
-    buffer: Vec<u8>
+pub struct Packet<T: AsRef<[u8]>> {
+    buffer: T
 }
 
 mod field {
@@ -164,84 +164,87 @@ mod field {
 }
 
 pub mod checksum {
-    use std::io::Cursor;
-    use byteorder::{ReadBytesExt, BigEndian};
-
-    pub fn calc(mut data: &[u8]) -> u16 {
-        let mut res: u32 = 0xffffu32;
-        let mut buffer = Cursor::new(data);
-        while let Ok(val) = buffer.read_u16::<BigEndian>() {
-            if buffer.position() == 4 {
-                continue;
-            }
-            res += val as u32;
-            if res > 0xffff {
-                res -= 0xffff;
-            }
-        }
-        !(res as u16)
+    use crate::packet::util;
+
+    pub fn calc(data: &[u8]) -> u16 {
+        util::checksum(data, super::field::CHECKSUM.start)
     }
 }
 
-impl Packet {
-    pub fn new(buffer: Vec<u8>) -> Result<Self, Error> {
-        Ok(Packet{buffer})
+impl<T: AsRef<[u8]>> Packet<T> {
+    pub fn new(buffer: T) -> Result<Self, Error> {
+        error::check_len(buffer.as_ref(), field::DATA.start)?;
+        let p = Packet{buffer};
+        // echo and unreachable messages carry 4 more header bytes
+        match p.typ() {
+            Type::EchoReply | Type::EchoRequest | Type::DstUnreachable =>
+                error::check_len(p.buffer.as_ref(), field::echo::DATA.start)?,
+            _ => (),
+        }
+        Ok(p)
     }
 
     pub fn header(&self) -> &[u8] {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         &b[0..field::DATA.start]
     }
 
     pub fn typ(&self) -> Type {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         Type::from(b[field::TYPE])
     }
 
     pub fn code(&self) -> u8 {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         b[field::CODE]
     }
 
     pub fn checksum(&self) -> u16 {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         BigEndian::read_u16(&b[field::CHECKSUM])
     }
 
+    // the checksum covers the whole message
+    pub fn check_checksum(&self) -> Result<(), Error> {
+        let (expected, got) = (checksum::calc(self.buffer.as_ref()), self.checksum());
+        if expected != got {
+            return Err(Error::from(ErrorKind::BadChecksum { expected, got }));
+        }
+        Ok(())
+    }
+
     pub fn data(&self) -> &[u8] {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         &b[field::DATA]
     }
 
+    // the typed accessors check the length again, set_type may have changed what new validated
     pub fn echo_ident(&self) -> Option<u16> {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         match self.typ() {
-            Type::EchoReply => Some(BigEndian::read_u16(&b[field::echo::IDENT])),
-            Type::EchoRequest => Some(BigEndian::read_u16(&b[field::echo::IDENT])),
+            Type::EchoReply | Type::EchoRequest => b.get(field::echo::IDENT).map(BigEndian::read_u16),
             _ => None
         }
     }
 
     pub fn echo_seqno(&self) -> Option<u16> {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         match self.typ() {
-            Type::EchoReply => Some(BigEndian::read_u16(&b[field::echo::SEQNO])),
-            Type::EchoRequest => Some(BigEndian::read_u16(&b[field::echo::SEQNO])),
+            Type::EchoReply | Type::EchoRequest => b.get(field::echo::SEQNO).map(BigEndian::read_u16),
             _ => None
         }
     }
 
     pub fn echo_data(&self) -> Option<&[u8]> {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         match self.typ() {
-            Type::EchoReply => Some(&b[field::echo::DATA]),
-            Type::EchoRequest => Some(&b[field::echo::DATA]),
+            Type::EchoReply | Type::EchoRequest => b.get(field::echo::DATA),
             _ => None
         }
     }
 
     pub fn unreachable_code(&self) -> Option<DstUnreachableCode> {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         match self.typ() {
             Type::DstUnreachable => Some(DstUnreachableCode::from(b[field::CODE])),
             _ => None,
@@ -249,55 +252,61 @@ impl Packet {
     }
 
     pub fn unreachable_nexthop(&self) -> Option<u16> {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         match self.typ() {
-            Type::DstUnreachable => Some(BigEndian::read_u16(&b[field::unreachable::NEXT])),
+            Type::DstUnreachable => b.get(field::unreachable::NEXT).map(BigEndian::read_u16),
             _ => None,
         }
     }
 
     pub fn unreachable_data(&self) -> Option<&[u8]> {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         match self.typ() {
-            Type::DstUnreachable => Some(&b[field::unreachable::DATA]),
+            Type::DstUnreachable => b.get(field::unreachable::DATA),
             _ => None
         }
     }
 
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Packet<T> {
     // setter
     pub fn set_type(&mut self, typ: Type) {
-        let mut b = self.buffer.as_mut_slice();
+        let mut b = self.buffer.as_mut();
         let v: u8 = typ.into();
         b[field::TYPE] = v;
     }
 
     pub fn set_code(&mut self, code: u8) {
-        let mut b = self.buffer.as_mut_slice();
+        let mut b = self.buffer.as_mut();
         b[field::CODE] = code;
     }
 
     pub fn set_checksum(&mut self, sum: u16) {
-        let mut b = self.buffer.as_mut_slice();
+        let mut b = self.buffer.as_mut();
         BigEndian::write_u16(&mut b[field::CHECKSUM], sum);
     }
 
     pub fn set_data(&mut self, data: &[u8]) {
-        let mut b = self.buffer.as_mut_slice();
+        let mut b = self.buffer.as_mut();
         b[field::DATA].copy_from_slice(data);
     }
 
     pub fn set_echo_ident(&mut self, ident: u16) {
-        let mut b = self.buffer.as_mut_slice();
+        let mut b = self.buffer.as_mut();
         BigEndian::write_u16(&mut b[field::echo::IDENT], ident);
     }
 
     pub fn set_echo_seqno(&mut self, no: u16) {
-        let mut b = self.buffer.as_mut_slice();
+        let mut b = self.buffer.as_mut();
         BigEndian::write_u16(&mut b[field::echo::SEQNO], no);
     }
 
     pub fn set_echo_data(&mut self, data: &[u8]) {
-        let mut b = self.buffer.as_mut_slice();
+        let mut b = self.buffer.as_mut();
         b[field::echo::DATA].copy_from_slice(data);
     }
 }
@@ -305,6 +314,7 @@ impl Packet {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     static ECHO_PACKET_BYTES: [u8; 12] =
         [0x08, 0x00, 0x8e, 0xfe,
@@ -334,7 +344,7 @@ mod tests {
         p.set_echo_ident(0x1234);
         p.set_echo_seqno(0xabcd);
         p.set_echo_data(&ECHO_DATA_BYTES);
-        p.set_checksum(checksum::calc(p.buffer.as_slice()));
+        p.set_checksum(checksum::calc(p.buffer.as_ref()));
         assert_eq!(p.typ(), Type::EchoRequest);
         assert_eq!(p.code(), 0);
         assert_eq!(p.checksum(), 0x8efe);
@@ -344,9 +354,95 @@ mod tests {
         assert_eq!(p.header(), &[0x08, 0x00, 0x8e, 0xfe])
     }
     #[test]
+    fn test_parse_stack_frame() {
+        use crate::packet::ethernet::{self, EtherType};
+        use crate::packet::ip_protocol::IpProtocol;
+        use crate::packet::ipv4;
+
+        // built and parsed in place, nothing below owns a copy
+        let mut buf = [0u8; ethernet::HEADER_LENGTH + 20 + 12];
+        let mut frame = ethernet::Frame::new(&mut buf[..]).unwrap();
+        frame.set_type(EtherType::Ipv4);
+        let mut ip = ipv4::Packet::new_unchecked(frame.mut_payload());
+        ip.set_version(ipv4::Version::Ipv4);
+        ip.set_header_length(20);
+        ip.set_length(32);
+        ip.set_protocol(IpProtocol::ICMP);
+        let mut icmp = Packet::new(ip.mut_payload()).unwrap();
+        icmp.set_type(Type::EchoRequest);
+        icmp.set_echo_ident(0x1234);
+
+        let frame = ethernet::Frame::new(&buf[..]).unwrap();
+        let ip = ipv4::Packet::new(frame.payload()).unwrap();
+        assert_eq!(ip.protocol(), IpProtocol::ICMP);
+        let icmp = Packet::new(ip.payload()).unwrap();
+        assert_eq!(icmp.typ(), Type::EchoRequest);
+        assert_eq!(icmp.echo_ident(), Some(0x1234));
+        assert_eq!(icmp.echo_data().unwrap(), &[0u8; 4]);
+    }
+    #[test]
+    fn test_truncated() {
+        let err = Packet::new(&ECHO_PACKET_BYTES[..3]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Truncated { needed: 4, got: 3 });
+        let err = Packet::new(&ECHO_PACKET_BYTES[..6]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Truncated { needed: 8, got: 6 });
+        let p = Packet::new(&ECHO_PACKET_BYTES[..8]).unwrap();
+        assert_eq!(p.echo_data().unwrap(), &[]);
+    }
+    #[test]
+    fn test_retyped_short_buffer() {
+        let mut p = Packet::new(vec![4u8, 0, 0, 0]).unwrap();
+        p.set_type(Type::EchoRequest);
+        assert_eq!((p.echo_ident(), p.echo_seqno(), p.echo_data()), (None, None, None));
+        p.set_type(Type::DstUnreachable);
+        assert_eq!((p.unreachable_nexthop(), p.unreachable_data()), (None, None));
+        assert_eq!(p.unreachable_code(), Some(DstUnreachableCode::NetworkUnreachable));
+    }
+    #[test]
+    fn test_check_checksum() {
+        let mut b = ECHO_PACKET_BYTES;
+        assert_eq!(Packet::new(&b[..]).unwrap().check_checksum(), Ok(()));
+        b[8] = 0;
+        let err = Packet::new(&b[..]).unwrap().check_checksum().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BadChecksum { expected: 0x38ff, got: 0x8efe });
+    }
+    #[test]
     fn test_calc_icmp_checksum() {
         let p = Packet::new(ECHO_PACKET_BYTES.to_vec()).unwrap();
-        let sum = checksum::calc(p.buffer.as_slice());
+        let sum = checksum::calc(p.buffer.as_ref());
         assert_eq!(sum, 0x8efe);
     }
-}
\ No newline at end of file
+
+    proptest! {
+        #[test]
+        fn test_round_trip(reply: bool, ident: u16, seqno: u16,
+                           data in prop::collection::vec(any::<u8>(), 0..1472)) {
+            let typ = if reply { Type::EchoReply } else { Type::EchoRequest };
+            let mut p = Packet::new(vec![0u8; 8 + data.len()]).unwrap();
+            p.set_type(typ);
+            p.set_code(0);
+            p.set_echo_ident(ident);
+            p.set_echo_seqno(seqno);
+            p.set_echo_data(&data);
+            let mut buf = p.into_inner();
+            let sum = checksum::calc(&buf);
+            Packet::new(&mut buf[..]).unwrap().set_checksum(sum);
+
+            let p = Packet::new(&buf[..]).unwrap();
+            prop_assert_eq!(p.check_checksum(), Ok(()));
+            prop_assert_eq!(p.typ(), typ);
+            prop_assert_eq!(p.code(), 0);
+            prop_assert_eq!(p.echo_ident(), Some(ident));
+            prop_assert_eq!(p.echo_seqno(), Some(seqno));
+            prop_assert_eq!(p.echo_data(), Some(&data[..]));
+        }
+        #[test]
+        fn test_parse_any(data in prop::collection::vec(any::<u8>(), 0..32)) {
+            if let Ok(p) = Packet::new(&data[..]) {
+                prop_assert_eq!(p.header().len() + p.data().len(), data.len());
+                let echo = p.echo_data().map(|d| d.len() + 8);
+                prop_assert!(echo.is_none() || echo == Some(data.len()));
+            }
+        }
+    }
+}
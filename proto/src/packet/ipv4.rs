@@ -1,8 +1,10 @@
-use crate::packet::error::Error;
+use crate::packet::error::{self, Error, ErrorKind};
 use std::path::Prefix::Verbatim;
 use byteorder::{BigEndian, ByteOrder};
 use crate::packet::ip_protocol::IpProtocol;
 
+pub const HEADER_LENGTH: usize = 20;
+
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
 pub struct IpAddress(pub [u8; 4]);
 
@@ -60,8 +62,8 @@ impl std::convert::From<Version> for u8 {
 pub enum Flag {
     NoMore = 0,
     DontFragment = 2,
-    MoreFragment = 4,
-    Unknown
+    MoreFragment = 1,
+    Unknown = 4
 }
 
 impl std::convert::From<u16> for Flag {
@@ -69,7 +71,7 @@ impl std::convert::From<u16> for Flag {
         match f {
             0 => Flag::NoMore,
             2 => Flag::DontFragment,
-            4 => Flag::MoreFragment,
+            1 => Flag::MoreFragment,
             _ => Flag::Unknown
         }
     }
@@ -80,15 +82,15 @@ impl std::convert::From<Flag> for u16 {
         match f {
             Flag::NoMore => 0,
             Flag::DontFragment => 2,
-            Flag::MoreFragment => 4,
-            Flag::Unknown => 1 // invalid flag
+            Flag::MoreFragment => 1,
+            Flag::Unknown => 4 // the reserved bit
         }
     }
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
-pub struct Packet {
-    buffer: Vec<u8>
+pub struct Packet<T: AsRef<[u8]>> {
+    buffer: T
 }
 
 mod field {
@@ -116,81 +118,92 @@ mod field {
 }
 
 pub mod checksum {
-    use std::io::Cursor;
-    use byteorder::{ReadBytesExt, BigEndian};
-
-    pub fn calc(mut data: &[u8]) -> u16 {
-        let mut res: u32 = 0xffffu32;
-        let mut buffer = Cursor::new(data);
-        while let Ok(val) = buffer.read_u16::<BigEndian>() {
-            if buffer.position() == 12 {
-                continue;
-            }
-            res += val as u32;
-            if res > 0xffff {
-                res -= 0xffff;
-            }
-        }
-        !(res as u16)
+    use crate::packet::util;
+
+    pub fn calc(data: &[u8]) -> u16 {
+        util::checksum(data, super::field::CHECKSUM.start)
     }
 }
 
-impl Packet {
-    pub fn new(buffer: Vec<u8>) -> Result<Self, Error> {
+impl<T: AsRef<[u8]>> Packet<T> {
+    pub fn new(buffer: T) -> Result<Self, Error> {
         let p = Packet { buffer };
+        p.is_valid()?;
         Ok(p)
     }
 
+    // for building a packet in a zeroed buffer, accessors may panic until the header is set
+    pub fn new_unchecked(buffer: T) -> Self {
+        Packet { buffer }
+    }
+
+    fn is_valid(&self) -> Result<(), Error> {
+        let b = self.buffer.as_ref();
+        error::check_len(b, HEADER_LENGTH)?;
+        if self.version() != Version::Ipv4 {
+            return Err(Error::from(ErrorKind::BadVersion));
+        }
+        let header = self.header_length() * 4;
+        if header < HEADER_LENGTH {
+            return Err(Error::from(ErrorKind::BadHeaderLength));
+        }
+        error::check_len(b, header)?;
+        if self.length() < header {
+            return Err(Error::from(ErrorKind::BadLength));
+        }
+        error::check_len(b, self.length())
+    }
+
     pub fn version(&self) -> Version {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         Version::from(b[field::VERSION_IHL] >> 4)
     }
 
     pub fn header_length(&self) -> usize {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         (b[field::VERSION_IHL] & 0x0f) as usize
     }
 
     pub fn tos(&self) -> u8 {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         b[field::TOS]
     }
 
     pub fn length(&self) -> usize {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         let l = BigEndian::read_u16(&b[field::LENGTH]);
         l as usize
     }
 
     pub fn identification(&self) -> u16 {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         BigEndian::read_u16(&b[field::IDENT])
     }
 
     pub fn flag(&self) -> Flag {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         let f = BigEndian::read_u16(&b[field::FLAG_OFFSET]) >> 13;
-        println!("{:04x}", f<<13);
         Flag::from(f)
     }
 
+    // in 8 byte units, as on the wire
     pub fn fragment(&self) -> u16 {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         BigEndian::read_u16(&b[field::FLAG_OFFSET]) & 0x1fff
     }
 
     pub fn ttl(&self) -> u8 {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         b[field::TTL]
     }
 
     pub fn protocol(&self) -> IpProtocol {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         IpProtocol::from(b[field::PROTOCOL])
     }
 
     pub fn checksum(&self) -> u16 {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         BigEndian::read_u16(&b[field::CHECKSUM])
     }
 
@@ -199,109 +212,129 @@ impl Packet {
         checksum::calc(&mut self.header()) == checksum
     }
 
+    pub fn check_checksum(&self) -> Result<(), Error> {
+        let (expected, got) = (checksum::calc(self.header()), self.checksum());
+        if expected != got {
+            return Err(Error::from(ErrorKind::BadChecksum { expected, got }));
+        }
+        Ok(())
+    }
+
     pub fn source_addr(&self) -> IpAddress {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         IpAddress::from_bytes(&b[field::SRC_ADDR])
     }
 
     pub fn destination_addr(&self) -> IpAddress {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         IpAddress::from_bytes(&b[field::DST_ADDR])
     }
 
-    pub fn option(&self) -> Vec<u8> {
-        let b = self.buffer.as_slice();
-        b[field::OPTION(self.header_length())].to_vec()
+    pub fn option(&self) -> &[u8] {
+        let b = self.buffer.as_ref();
+        &b[field::OPTION(self.header_length())]
     }
 
     pub fn header(&self) -> &[u8] {
-        let b = self.buffer.as_slice();
+        let b = self.buffer.as_ref();
         &b[0..self.header_length()*4]
     }
 
-    pub fn payload(&self) -> Vec<u8> {
-        let b = self.buffer.as_slice();
-        b[field::PAYLOAD(self.header_length(), self.length())].to_vec()
+    // borrowed, so it can be parsed further without a copy
+    pub fn payload(&self) -> &[u8] {
+        let b = self.buffer.as_ref();
+        &b[field::PAYLOAD(self.header_length(), self.length())]
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Packet<T> {
+    pub fn mut_payload(&mut self) -> &mut [u8] {
+        let (ihl, length) = (self.header_length(), self.length());
+        let b = self.buffer.as_mut();
+        &mut b[field::PAYLOAD(ihl, length)]
     }
 
     // setter
     pub fn set_version(&mut self, ver: Version) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         let v: u8 = ver.into();
         b[field::VERSION_IHL] = (b[field::VERSION_IHL] & 0x0f)  + (v << 4);
     }
 
     pub fn set_header_length(&mut self, ihl: usize) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         b[field::VERSION_IHL] = (b[field::VERSION_IHL] & 0xf0) + (ihl as u8 / 4);
     }
 
     pub fn set_tos(&mut self, tos: u8) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         b[field::TOS] = tos;
     }
 
     pub fn set_length(&mut self, length: usize) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         BigEndian::write_u16(&mut b[field::LENGTH], length as u16);
     }
 
     pub fn set_identification(&mut self, ident: u16) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         BigEndian::write_u16(&mut b[field::IDENT], ident);
     }
 
     pub fn set_flag(&mut self, flag: Flag) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         let s = BigEndian::read_u16(&b[field::FLAG_OFFSET]);
         let flag: u16 = flag.into();
-        let f = (s & !0xe000) + flag << 13;
+        let f = (s & !0xe000) | (flag << 13);
         BigEndian::write_u16(&mut b[field::FLAG_OFFSET], f);
     }
 
+    // in 8 byte units like fragment
     pub fn set_fragment_offset(&mut self, offset: u16) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         let o = BigEndian::read_u16(&b[field::FLAG_OFFSET]);
-        println!("{:04x}", o);
-        let f = (o & 0xe000) + (offset >> 3);
-        println!("{:04x}", f);
+        let f = (o & 0xe000) | (offset & 0x1fff);
         BigEndian::write_u16(&mut b[field::FLAG_OFFSET], f);
     }
 
     pub fn set_ttl(&mut self, ttl: u8) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         b[field::TTL] = ttl;
     }
 
     pub fn set_protocol(&mut self, proto: IpProtocol) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         b[field::PROTOCOL] = proto.into();
     }
 
     pub fn set_checksum(&mut self, sum: u16) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         BigEndian::write_u16(&mut b[field::CHECKSUM], sum);
     }
 
     pub fn set_source_addr(&mut self, src: IpAddress) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         b[field::SRC_ADDR].copy_from_slice(src.as_bytes());
     }
 
     pub fn set_destination_addr(&mut self, dst: IpAddress) {
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         b[field::DST_ADDR].copy_from_slice(dst.as_bytes());
     }
 
     pub fn set_option(&mut self, option: &[u8]) {
         let ihl = self.header_length();
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         b[field::OPTION(ihl)].copy_from_slice(option);
     }
 
     pub fn set_payload(&mut self, payload: &[u8]) {
         let (ihl, length) = (self.header_length(), self.length());
-        let b = self.buffer.as_mut_slice();
+        let b = self.buffer.as_mut();
         b[field::PAYLOAD(ihl, length)].copy_from_slice(payload);
     }
 }
@@ -311,6 +344,7 @@ impl Packet {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_is_broadcast() {
@@ -324,7 +358,7 @@ mod tests {
     static PACKET_BYTES: [u8; 28] =
         [0x46, 0x00, 0x00, 0x1c,
             0x00, 0x00, 0x40, 0x00,
-            0x40, 0x01, 0xd2, 0x79,
+            0x40, 0x01, 0xd1, 0x75,
             0x11, 0x12, 0x13, 0x14,
             0x21, 0x22, 0x23, 0x24,
             0xff, 0xff, 0xff, 0xff,
@@ -341,7 +375,7 @@ mod tests {
         assert_eq!(p.fragment(), 0);
         assert_eq!(p.ttl(), 0x40);
         assert_eq!(p.protocol(), IpProtocol::ICMP);
-        assert_eq!(p.checksum(), 0xd279);
+        assert_eq!(p.checksum(), 0xd175);
         assert_eq!(p.source_addr(), IpAddress::new(0x11,0x12,0x13,0x14));
         assert_eq!(p.destination_addr(), IpAddress::new(0x21,0x22,0x23,0x24));
         assert_eq!(p.option(), vec![0xff,0xff,0xff,0xff]);
@@ -349,7 +383,7 @@ mod tests {
     }
     #[test]
     fn test_build_ip_packet() {
-        let mut p = Packet::new(vec![0u8;28]).unwrap();
+        let mut p = Packet::new_unchecked(vec![0u8;28]);
         p.set_version(Version::Ipv4);
         p.set_header_length(24);
         p.set_tos(0);
@@ -359,7 +393,7 @@ mod tests {
         p.set_fragment_offset(0);
         p.set_ttl(0x40);
         p.set_protocol(IpProtocol::ICMP);
-        p.set_checksum(0xd279);
+        p.set_checksum(0xd175);
         p.set_source_addr(IpAddress::new(0x11,0x12,0x13,0x14));
         p.set_destination_addr(IpAddress::new(0x21,0x22,0x23,0x24));
         p.set_option(&[0xff;4]);
@@ -374,18 +408,67 @@ mod tests {
         assert_eq!(p.fragment(), 0);
         assert_eq!(p.ttl(), 0x40);
         assert_eq!(p.protocol(), IpProtocol::ICMP);
-        assert_eq!(p.checksum(), 0xd279);
+        assert_eq!(p.checksum(), 0xd175);
         assert_eq!(p.source_addr(), IpAddress::new(0x11,0x12,0x13,0x14));
         assert_eq!(p.destination_addr(), IpAddress::new(0x21,0x22,0x23,0x24));
         assert_eq!(p.option(), vec![0xff,0xff,0xff,0xff]);
         assert_eq!(p.payload(), vec![0xaa,0x00,0x00,0xff]);
     }
     #[test]
+    fn test_fragment() {
+        // the fragments of a 3000 byte ping over a 1500 byte mtu
+        let mut b = PACKET_HEADER;
+        b[6..8].copy_from_slice(&[0x20, 0x00]);
+        let p = Packet::new_unchecked(&b[..]);
+        assert_eq!((p.flag(), p.fragment()), (Flag::MoreFragment, 0));
+        b[6..8].copy_from_slice(&[0x20, 0xb9]);
+        let p = Packet::new_unchecked(&b[..]);
+        assert_eq!((p.flag(), p.fragment()), (Flag::MoreFragment, 185));
+        b[6..8].copy_from_slice(&[0x01, 0x72]);
+        let p = Packet::new_unchecked(&b[..]);
+        assert_eq!((p.flag(), p.fragment()), (Flag::NoMore, 370));
+        b[6..8].copy_from_slice(&[0x80, 0x00]);
+        assert_eq!(Packet::new_unchecked(&b[..]).flag(), Flag::Unknown);
+
+        let mut p = Packet::new_unchecked(PACKET_HEADER);
+        p.set_flag(Flag::MoreFragment);
+        p.set_fragment_offset(185);
+        assert_eq!(&p.into_inner()[6..8], &[0x20, 0xb9]);
+    }
+    #[test]
+    fn test_invalid_packet() {
+        let kind = |b: &[u8]| Packet::new(b).unwrap_err().kind();
+        assert_eq!(kind(&PACKET_BYTES[..19]), ErrorKind::Truncated { needed: 20, got: 19 });
+        assert_eq!(kind(&PACKET_BYTES[..22]), ErrorKind::Truncated { needed: 24, got: 22 });
+        assert_eq!(kind(&PACKET_BYTES[..27]), ErrorKind::Truncated { needed: 28, got: 27 });
+        let mut b = PACKET_BYTES;
+        b[0] = 0x66;
+        assert_eq!(kind(&b), ErrorKind::BadVersion);
+        b[0] = 0x44;
+        assert_eq!(kind(&b), ErrorKind::BadHeaderLength);
+        b[0] = 0x46;
+        b[3] = 0x14;
+        assert_eq!(kind(&b), ErrorKind::BadLength);
+        // trailing bytes past the total length are padding
+        let mut padded = PACKET_BYTES.to_vec();
+        padded.extend_from_slice(&[0; 18]);
+        assert_eq!(Packet::new(&padded).unwrap().payload(), &[0xaa, 0x00, 0x00, 0xff]);
+    }
+    #[test]
+    fn test_check_checksum() {
+        let mut b = [0u8; 0x34];
+        b[..20].copy_from_slice(&PACKET_HEADER);
+        assert_eq!(Packet::new(&b[..]).unwrap().check_checksum(), Ok(()));
+        b[11] = 0;
+        let err = Packet::new(&b[..]).unwrap().check_checksum().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BadChecksum { expected: 0x0821, got: 0x0800 });
+    }
+    #[test]
     fn test_ip_header() {
         let header: [u8; 24] =
             [0x46, 0x00, 0x00, 0x1c,
                 0x00, 0x00, 0x40, 0x00,
-                0x40, 0x01, 0xd2, 0x79,
+                0x40, 0x01, 0xd1, 0x75,
                 0x11, 0x12, 0x13, 0x14,
                 0x21, 0x22, 0x23, 0x24,
                 0xff, 0xff, 0xff, 0xff];
@@ -408,4 +491,55 @@ mod tests {
         let mut p = Packet::new(PACKET_BYTES.to_vec()).unwrap();
         assert_eq!(p.verify_checksum(), true)
     }
-}
\ No newline at end of file
+
+    proptest! {
+        #[test]
+        fn test_round_trip(tos: u8, ident: u16, ttl: u8, offset in 0u16..0x2000,
+                           flag in prop::sample::select(vec![Flag::NoMore, Flag::DontFragment, Flag::MoreFragment]),
+                           proto in prop::sample::select(vec![IpProtocol::ICMP, IpProtocol::TCP, IpProtocol::UDP]),
+                           src: [u8; 4], dst: [u8; 4], words in 0usize..=10,
+                           payload in prop::collection::vec(any::<u8>(), 0..1480)) {
+            let header = HEADER_LENGTH + words * 4;
+            let option: Vec<u8> = (0..words * 4).map(|i| i as u8).collect();
+            let mut p = Packet::new_unchecked(vec![0u8; header + payload.len()]);
+            p.set_version(Version::Ipv4);
+            p.set_header_length(header);
+            p.set_tos(tos);
+            p.set_length(header + payload.len());
+            p.set_identification(ident);
+            p.set_fragment_offset(offset);
+            p.set_flag(flag);
+            p.set_ttl(ttl);
+            p.set_protocol(proto);
+            p.set_source_addr(IpAddress(src));
+            p.set_destination_addr(IpAddress(dst));
+            p.set_option(&option);
+            p.set_payload(&payload);
+            let sum = checksum::calc(p.header());
+            p.set_checksum(sum);
+
+            let buf = p.into_inner();
+            let p = Packet::new(&buf[..]).unwrap();
+            prop_assert_eq!(p.check_checksum(), Ok(()));
+            prop_assert_eq!(p.header_length() * 4, header);
+            prop_assert_eq!(p.tos(), tos);
+            prop_assert_eq!(p.length(), buf.len());
+            prop_assert_eq!(p.identification(), ident);
+            prop_assert_eq!(p.flag(), flag);
+            prop_assert_eq!(p.fragment(), offset);
+            prop_assert_eq!(p.ttl(), ttl);
+            prop_assert_eq!(p.protocol(), proto);
+            prop_assert_eq!(p.source_addr(), IpAddress(src));
+            prop_assert_eq!(p.destination_addr(), IpAddress(dst));
+            prop_assert_eq!(p.option(), &option[..]);
+            prop_assert_eq!(p.payload(), &payload[..]);
+        }
+        #[test]
+        fn test_parse_any(data in prop::collection::vec(any::<u8>(), 0..96)) {
+            if let Ok(p) = Packet::new(&data[..]) {
+                prop_assert_eq!(p.header().len() + p.payload().len(), p.length());
+                prop_assert_eq!(p.option().len(), p.header().len() - HEADER_LENGTH);
+            }
+        }
+    }
+}
@@ -1,5 +1,30 @@
-//
-// pub fn checksum(data: &[u8]) -> u16 {
-//     let mut sum = 0;
-//
-// }
\ No newline at end of file
+// internet checksum (rfc 1071) of data, with the 16 bit word at offset skip taken as zero.
+// an odd trailing byte is padded with zero
+pub fn checksum(data: &[u8], skip: usize) -> u16 {
+    let mut sum = 0u32;
+    for (i, word) in data.chunks(2).enumerate() {
+        if i * 2 == skip {
+            continue;
+        }
+        let lo = word.get(1).copied().unwrap_or(0);
+        sum += u32::from(u16::from_be_bytes([word[0], lo]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // rfc 1071 section 3 example, summed with the checksum field at the end
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7, 0xff, 0xff];
+        assert_eq!(checksum(&data, 8), !0xddf2);
+        assert_eq!(checksum(&[0x01], 2), !0x0100);
+        assert_eq!(checksum(&[], 0), 0xffff);
+    }
+}
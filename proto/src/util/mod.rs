@@ -4,11 +4,13 @@ use std::fs::File;
 use std::io::{BufWriter, Write};
 use byteorder::WriteBytesExt;
 
+pub mod netlink;
+pub mod netns;
+
 pub fn cmd(cmd: &str, args: Vec<&str>) -> Result<ExitStatus, io::Error> {
     Command::new(cmd)
         .args(&args)
-        .spawn()
-        .unwrap()
+        .spawn()?
         .wait()
 }
 
@@ -17,4 +19,4 @@ pub fn disable_ipv4_forward() {
     let mut writer = BufWriter::new(file);
     writer.write("0".as_bytes()).unwrap();
     drop(writer);
-}
\ No newline at end of file
+}
@@ -0,0 +1,570 @@
+use std::cell::Cell;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use byteorder::{ByteOrder, NativeEndian};
+use nix::unistd::close;
+use thiserror::Error;
+use crate::device::iface::{interface_index, IFNAMSIZ};
+use crate::packet::ethernet::MACAddress;
+use crate::packet::ipv4::IpAddress;
+
+
+const NETLINK_ROUTE: libc::c_int = 0;
+
+const NLMSG_ERROR: u16 = 0x2;
+const NLMSG_DONE: u16 = 0x3;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_GETLINK: u16 = 18;
+const RTM_SETLINK: u16 = 19;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+
+const IFLA_ADDRESS: u16 = 1;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_XDP: u16 = 43;
+
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+
+const VETH_INFO_PEER: u16 = 1;
+
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_EXPECTED_FD: u16 = 8;
+
+const NLA_F_NESTED: u16 = 0x8000;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+const RT_SCOPE_UNIVERSE: u8 = 0;
+
+pub const IFF_UP: u32 = 0x1;
+pub const IFF_RUNNING: u32 = 0x40;
+
+pub const XDP_FLAGS_UPDATE_IF_NOEXIST: u32 = 1 << 0;
+pub const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+pub const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+pub const XDP_FLAGS_REPLACE: u32 = 1 << 4;
+
+const RECV_BUFFER_SIZE: usize = 32768;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("netlink socket error: {0}")]
+    Io(#[from] io::Error),
+    #[error("kernel rejected request: {}", io::Error::from_raw_os_error(*.errno))]
+    Kernel { errno: i32 },
+    #[error("no such interface: {0}")]
+    NoSuchInterface(String),
+    #[error("invalid interface name: {0:?}")]
+    InvalidName(String),
+    #[error("invalid prefix length: {0}")]
+    InvalidPrefix(u8),
+    #[error("malformed netlink message")]
+    Malformed,
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(e) => e,
+            Error::Kernel { errno } => io::Error::from_raw_os_error(errno),
+            Error::NoSuchInterface(_) => io::Error::new(io::ErrorKind::NotFound, err),
+            Error::InvalidName(_) | Error::InvalidPrefix(_) => io::Error::new(io::ErrorKind::InvalidInput, err),
+            Error::Malformed => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+mod field {
+    use std::ops::Range;
+
+    // struct nlmsghdr
+    pub const NLMSG_LEN: Range<usize> = 0..4;
+    pub const NLMSG_TYPE: Range<usize> = 4..6;
+    pub const NLMSG_FLAGS: Range<usize> = 6..8;
+    pub const NLMSG_SEQ: Range<usize> = 8..12;
+    pub const NLMSG_HEADER_LENGTH: usize = 16;
+
+    // struct ifinfomsg
+    pub const IFI_FAMILY: usize = 0;
+    pub const IFI_INDEX: Range<usize> = 4..8;
+    pub const IFI_FLAGS: Range<usize> = 8..12;
+    pub const IFI_CHANGE: Range<usize> = 12..16;
+    pub const IFINFOMSG_LENGTH: usize = 16;
+
+    // struct ifaddrmsg
+    pub const IFA_FAMILY: usize = 0;
+    pub const IFA_PREFIXLEN: usize = 1;
+    pub const IFA_FLAGS: usize = 2;
+    pub const IFA_SCOPE: usize = 3;
+    pub const IFA_INDEX: Range<usize> = 4..8;
+    pub const IFADDRMSG_LENGTH: usize = 8;
+
+    // struct rtattr
+    pub const RTA_LEN: Range<usize> = 0..2;
+    pub const RTA_TYPE: Range<usize> = 2..4;
+    pub const RTA_HEADER_LENGTH: usize = 4;
+
+    #[inline]
+    pub fn align(len: usize) -> usize {
+        (len + 3) & !3
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Link {
+    pub index: u32,
+    pub name: String,
+    pub flags: u32,
+    pub mtu: u32,
+    pub mac: Option<MACAddress>,
+}
+
+impl Link {
+    pub fn is_up(&self) -> bool {
+        self.flags & IFF_UP != 0
+    }
+}
+
+// netlink request under construction
+struct Message {
+    buffer: Vec<u8>,
+}
+
+impl Message {
+    fn new(typ: u16, flags: u16, seq: u32) -> Message {
+        let mut buffer = vec![0u8; field::NLMSG_HEADER_LENGTH];
+        NativeEndian::write_u16(&mut buffer[field::NLMSG_TYPE], typ);
+        NativeEndian::write_u16(&mut buffer[field::NLMSG_FLAGS], flags);
+        NativeEndian::write_u32(&mut buffer[field::NLMSG_SEQ], seq);
+        Message { buffer }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+        self.buffer.resize(field::align(self.buffer.len()), 0);
+    }
+
+    fn push_attr(&mut self, typ: u16, data: &[u8]) {
+        self.push(&attr(typ, data));
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buffer.len() as u32;
+        NativeEndian::write_u32(&mut self.buffer[field::NLMSG_LEN], len);
+        self.buffer
+    }
+}
+
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> [u8; field::IFINFOMSG_LENGTH] {
+    let mut msg = [0u8; field::IFINFOMSG_LENGTH];
+    msg[field::IFI_FAMILY] = libc::AF_UNSPEC as u8;
+    NativeEndian::write_u32(&mut msg[field::IFI_INDEX], index);
+    NativeEndian::write_u32(&mut msg[field::IFI_FLAGS], flags);
+    NativeEndian::write_u32(&mut msg[field::IFI_CHANGE], change);
+    msg
+}
+
+fn ifaddrmsg(index: u32, prefix: u8) -> [u8; field::IFADDRMSG_LENGTH] {
+    let mut msg = [0u8; field::IFADDRMSG_LENGTH];
+    msg[field::IFA_FAMILY] = libc::AF_INET as u8;
+    msg[field::IFA_PREFIXLEN] = prefix;
+    msg[field::IFA_FLAGS] = 0;
+    msg[field::IFA_SCOPE] = RT_SCOPE_UNIVERSE;
+    NativeEndian::write_u32(&mut msg[field::IFA_INDEX], index);
+    msg
+}
+
+// encode a single rtattr, padded to alignment
+fn attr(typ: u16, data: &[u8]) -> Vec<u8> {
+    let len = field::RTA_HEADER_LENGTH + data.len();
+    let mut buf = vec![0u8; field::align(len)];
+    NativeEndian::write_u16(&mut buf[field::RTA_LEN], len as u16);
+    NativeEndian::write_u16(&mut buf[field::RTA_TYPE], typ);
+    buf[field::RTA_HEADER_LENGTH..len].copy_from_slice(data);
+    buf
+}
+
+// NUL terminated IFLA_IFNAME payload
+fn ifname(name: &str) -> Result<Vec<u8>> {
+    if name.is_empty() || name.len() > (IFNAMSIZ - 1) || name.as_bytes().contains(&0) {
+        return Err(Error::InvalidName(name.to_string()));
+    }
+    let mut data = name.as_bytes().to_vec();
+    data.push(0);
+    Ok(data)
+}
+
+// iterate over (type, payload) of the rtattrs in buf
+fn attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < field::RTA_HEADER_LENGTH {
+            return None;
+        }
+        let len = NativeEndian::read_u16(&buf[field::RTA_LEN]) as usize;
+        if len < field::RTA_HEADER_LENGTH || len > buf.len() {
+            return None;
+        }
+        let typ = NativeEndian::read_u16(&buf[field::RTA_TYPE]);
+        let data = &buf[field::RTA_HEADER_LENGTH..len];
+        buf = &buf[field::align(len).min(buf.len())..];
+        Some((typ, data))
+    })
+}
+
+// parse the payload of a RTM_NEWLINK message
+fn parse_link(payload: &[u8]) -> Result<Link> {
+    if payload.len() < field::IFINFOMSG_LENGTH {
+        return Err(Error::Malformed);
+    }
+    let mut link = Link {
+        index: NativeEndian::read_u32(&payload[field::IFI_INDEX]),
+        name: String::new(),
+        flags: NativeEndian::read_u32(&payload[field::IFI_FLAGS]),
+        mtu: 0,
+        mac: None,
+    };
+    for (typ, data) in attrs(&payload[field::IFINFOMSG_LENGTH..]) {
+        match typ {
+            IFLA_IFNAME => {
+                link.name = data.iter().take_while(|&&c| c != 0).map(|&c| c as char).collect();
+            }
+            IFLA_MTU if data.len() == 4 => link.mtu = NativeEndian::read_u32(data),
+            IFLA_ADDRESS if data.len() == 6 => link.mac = Some(MACAddress::from_bytes(data)),
+            _ => {}
+        }
+    }
+    Ok(link)
+}
+
+// rtnetlink socket for interface configuration
+#[derive(Debug)]
+pub struct Netlink {
+    fd: RawFd,
+    seq: Cell<u32>,
+}
+
+impl AsRawFd for Netlink {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Netlink {
+    pub fn new() -> Result<Netlink> {
+        let fd = unsafe {
+            let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, NETLINK_ROUTE);
+            if fd == -1 { return Err(io::Error::last_os_error().into()) }
+            fd
+        };
+        Ok(Netlink {
+            fd,
+            seq: Cell::new(1),
+        })
+    }
+
+    pub fn index(&self, name: &str) -> Result<u32> {
+        if name.is_empty() {
+            return Err(Error::InvalidName(name.to_string()));
+        }
+        match interface_index(name) {
+            Ok(index) => Ok(index as u32),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => Err(Error::InvalidName(name.to_string())),
+            Err(_) => Err(Error::NoSuchInterface(name.to_string())),
+        }
+    }
+
+    pub fn set_up(&self, name: &str) -> Result<()> {
+        self.set_flags(name, IFF_UP, IFF_UP)
+    }
+
+    pub fn set_down(&self, name: &str) -> Result<()> {
+        self.set_flags(name, 0, IFF_UP)
+    }
+
+    // change only the bits set in mask
+    pub fn set_flags(&self, name: &str, flags: u32, mask: u32) -> Result<()> {
+        let index = self.index(name)?;
+        let mut msg = Message::new(RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK, self.next_seq());
+        msg.push(&ifinfomsg(index, flags, mask));
+        self.request(msg)
+    }
+
+    pub fn set_mtu(&self, name: &str, mtu: u32) -> Result<()> {
+        let index = self.index(name)?;
+        let mut msg = Message::new(RTM_SETLINK, NLM_F_REQUEST | NLM_F_ACK, self.next_seq());
+        msg.push(&ifinfomsg(index, 0, 0));
+        let mut data = [0u8; 4];
+        NativeEndian::write_u32(&mut data, mtu);
+        msg.push_attr(IFLA_MTU, &data);
+        self.request(msg)
+    }
+
+    pub fn set_mac(&self, name: &str, mac: MACAddress) -> Result<()> {
+        let index = self.index(name)?;
+        let mut msg = Message::new(RTM_SETLINK, NLM_F_REQUEST | NLM_F_ACK, self.next_seq());
+        msg.push(&ifinfomsg(index, 0, 0));
+        msg.push_attr(IFLA_ADDRESS, mac.as_bytes());
+        self.request(msg)
+    }
+
+    // attach an xdp program, a negative fd detaches the current one
+    pub fn set_xdp(&self, name: &str, fd: RawFd, flags: u32) -> Result<()> {
+        let index = self.index(name)?;
+        let mut msg = Message::new(RTM_SETLINK, NLM_F_REQUEST | NLM_F_ACK, self.next_seq());
+        msg.push(&ifinfomsg(index, 0, 0));
+        let mut data = [0u8; 4];
+        NativeEndian::write_i32(&mut data, fd);
+        let mut nested = attr(IFLA_XDP_FD, &data);
+        NativeEndian::write_u32(&mut data, flags);
+        nested.extend_from_slice(&attr(IFLA_XDP_FLAGS, &data));
+        msg.push_attr(IFLA_XDP | NLA_F_NESTED, &nested);
+        self.request(msg)
+    }
+
+    // detach only if the attached program is still expected, fails with EEXIST otherwise
+    pub fn clear_xdp(&self, name: &str, expected: RawFd, flags: u32) -> Result<()> {
+        let index = self.index(name)?;
+        let mut msg = Message::new(RTM_SETLINK, NLM_F_REQUEST | NLM_F_ACK, self.next_seq());
+        msg.push(&ifinfomsg(index, 0, 0));
+        let mut data = [0u8; 4];
+        NativeEndian::write_i32(&mut data, -1);
+        let mut nested = attr(IFLA_XDP_FD, &data);
+        NativeEndian::write_i32(&mut data, expected);
+        nested.extend_from_slice(&attr(IFLA_XDP_EXPECTED_FD, &data));
+        NativeEndian::write_u32(&mut data, flags | XDP_FLAGS_REPLACE);
+        nested.extend_from_slice(&attr(IFLA_XDP_FLAGS, &data));
+        msg.push_attr(IFLA_XDP | NLA_F_NESTED, &nested);
+        self.request(msg)
+    }
+
+    // create a veth pair, both ends start down
+    pub fn add_veth(&self, name: &str, peer: &str) -> Result<()> {
+        let mut peer_info = ifinfomsg(0, 0, 0).to_vec();
+        peer_info.extend_from_slice(&attr(IFLA_IFNAME, &ifname(peer)?));
+        let mut info = attr(IFLA_INFO_KIND, b"veth");
+        info.extend_from_slice(&attr(IFLA_INFO_DATA | NLA_F_NESTED, &attr(VETH_INFO_PEER | NLA_F_NESTED, &peer_info)));
+
+        let mut msg = Message::new(RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL, self.next_seq());
+        msg.push(&ifinfomsg(0, 0, 0));
+        msg.push_attr(IFLA_IFNAME, &ifname(name)?);
+        msg.push_attr(IFLA_LINKINFO | NLA_F_NESTED, &info);
+        self.request(msg)
+    }
+
+    // deleting one end of a veth pair removes the peer too
+    pub fn del_link(&self, name: &str) -> Result<()> {
+        let index = self.index(name)?;
+        let mut msg = Message::new(RTM_DELLINK, NLM_F_REQUEST | NLM_F_ACK, self.next_seq());
+        msg.push(&ifinfomsg(index, 0, 0));
+        self.request(msg)
+    }
+
+    pub fn add_address(&self, name: &str, addr: IpAddress, prefix: u8) -> Result<()> {
+        self.address(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, name, addr, prefix)
+    }
+
+    pub fn del_address(&self, name: &str, addr: IpAddress, prefix: u8) -> Result<()> {
+        self.address(RTM_DELADDR, 0, name, addr, prefix)
+    }
+
+    pub fn links(&self) -> Result<Vec<Link>> {
+        let seq = self.next_seq();
+        let mut msg = Message::new(RTM_GETLINK, NLM_F_REQUEST | NLM_F_DUMP, seq);
+        msg.push(&ifinfomsg(0, 0, 0));
+        self.send(&msg.finish())?;
+
+        let mut links = Vec::new();
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            let len = self.recv(&mut buf)?;
+            let mut done = false;
+            self.walk(&buf[..len], seq, |typ, payload| {
+                match typ {
+                    NLMSG_DONE => done = true,
+                    RTM_NEWLINK => links.push(parse_link(payload)?),
+                    _ => {}
+                }
+                Ok(())
+            })?;
+            if done {
+                return Ok(links);
+            }
+        }
+    }
+
+    pub fn link(&self, name: &str) -> Result<Link> {
+        let index = self.index(name)?;
+        self.links()?
+            .into_iter()
+            .find(|l| l.index == index)
+            .ok_or_else(|| Error::NoSuchInterface(name.to_string()))
+    }
+
+    fn address(&self, typ: u16, flags: u16, name: &str, addr: IpAddress, prefix: u8) -> Result<()> {
+        if prefix > 32 {
+            return Err(Error::InvalidPrefix(prefix));
+        }
+        let index = self.index(name)?;
+        let mut msg = Message::new(typ, NLM_F_REQUEST | NLM_F_ACK | flags, self.next_seq());
+        msg.push(&ifaddrmsg(index, prefix));
+        msg.push_attr(IFA_LOCAL, addr.as_bytes());
+        msg.push_attr(IFA_ADDRESS, addr.as_bytes());
+        self.request(msg)
+    }
+
+    // send a request and wait for its ack
+    fn request(&self, msg: Message) -> Result<()> {
+        let buf = msg.finish();
+        let seq = NativeEndian::read_u32(&buf[field::NLMSG_SEQ]);
+        self.send(&buf)?;
+
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            let len = self.recv(&mut buf)?;
+            let mut acked = false;
+            self.walk(&buf[..len], seq, |typ, _payload| {
+                if typ == NLMSG_ERROR {
+                    acked = true;
+                }
+                Ok(())
+            })?;
+            if acked {
+                return Ok(());
+            }
+        }
+    }
+
+    // call f for each message answering seq, NLMSG_ERROR with an errno is returned as Err
+    fn walk<F>(&self, mut buf: &[u8], seq: u32, mut f: F) -> Result<()>
+        where F: FnMut(u16, &[u8]) -> Result<()>
+    {
+        while buf.len() >= field::NLMSG_HEADER_LENGTH {
+            let len = NativeEndian::read_u32(&buf[field::NLMSG_LEN]) as usize;
+            if len < field::NLMSG_HEADER_LENGTH || len > buf.len() {
+                return Err(Error::Malformed);
+            }
+            let typ = NativeEndian::read_u16(&buf[field::NLMSG_TYPE]);
+            let payload = &buf[field::NLMSG_HEADER_LENGTH..len];
+            if NativeEndian::read_u32(&buf[field::NLMSG_SEQ]) == seq {
+                if typ == NLMSG_ERROR {
+                    if payload.len() < 4 {
+                        return Err(Error::Malformed);
+                    }
+                    let errno = -NativeEndian::read_i32(&payload[0..4]);
+                    if errno != 0 {
+                        return Err(Error::Kernel { errno });
+                    }
+                }
+                f(typ, payload)?;
+            }
+            buf = &buf[field::align(len).min(buf.len())..];
+        }
+        Ok(())
+    }
+
+    fn next_seq(&self) -> u32 {
+        let seq = self.seq.get();
+        self.seq.set(seq.wrapping_add(1));
+        seq
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<()> {
+        let res = unsafe { libc::send(self.fd, buf.as_ptr() as *const libc::c_void, buf.len(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let res = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(res as usize)
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RTM_NEWLINK payload for "lo" with mtu 65536
+    static LINK_BYTES: [u8; 44] =
+        [0x00, 0x00, 0x04, 0x03,
+            0x01, 0x00, 0x00, 0x00,
+            0x49, 0x00, 0x01, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x07, 0x00, 0x03, 0x00,
+            0x6c, 0x6f, 0x00, 0x00,
+            0x08, 0x00, 0x04, 0x00,
+            0x00, 0x00, 0x01, 0x00,
+            0x0a, 0x00, 0x01, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn test_parse_link() {
+        // the fixture is little endian
+        if cfg!(target_endian = "big") {
+            return;
+        }
+        let link = parse_link(&LINK_BYTES).unwrap();
+        assert_eq!(link.index, 1);
+        assert_eq!(link.name, "lo");
+        assert_eq!(link.mtu, 65536);
+        assert_eq!(link.mac, Some(MACAddress::default()));
+        assert!(link.is_up());
+    }
+    #[test]
+    fn test_message() {
+        let mut msg = Message::new(RTM_SETLINK, NLM_F_REQUEST, 7);
+        msg.push(&ifinfomsg(2, 0, 0));
+        msg.push_attr(IFLA_ADDRESS, MACAddress::BROADCAST.as_bytes());
+        let buf = msg.finish();
+        assert_eq!(buf.len(), 16 + 16 + 12);
+        assert_eq!(NativeEndian::read_u32(&buf[field::NLMSG_LEN]), 44);
+        assert_eq!(NativeEndian::read_u32(&buf[field::NLMSG_SEQ]), 7);
+        assert_eq!(attrs(&buf[32..]).collect::<Vec<_>>(), vec![(IFLA_ADDRESS, MACAddress::BROADCAST.as_bytes())]);
+    }
+    #[test]
+    fn test_invalid_name() {
+        let nl = Netlink::new().unwrap();
+        match nl.index("this-name-is-too-long") {
+            Err(Error::InvalidName(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match nl.set_up("rusproto-none") {
+            Err(Error::NoSuchInterface(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+    #[test]
+    fn test_links() {
+        let nl = Netlink::new().unwrap();
+        let lo = nl.link("lo").unwrap();
+        assert_eq!(lo.name, "lo");
+        assert!(lo.is_up());
+    }
+}
@@ -0,0 +1,223 @@
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic;
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::fcntl;
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::sys::stat;
+use nix::unistd::{close, getgid, getuid};
+use crate::device::raw_socket::RawSocketDevice;
+use crate::device::tuntap::TapDevice;
+use crate::packet::ipv4::IpAddress;
+use crate::util::netlink::Netlink;
+
+const THREAD_NETNS: &str = "/proc/thread-self/ns/net";
+
+fn open_netns() -> io::Result<RawFd> {
+    fcntl::open(THREAD_NETNS, fcntl::OFlag::O_RDONLY | fcntl::OFlag::O_CLOEXEC, stat::Mode::empty())
+        .map_err(|_| io::Error::last_os_error())
+}
+
+// make an unprivileged process root of a fresh user namespace, once. the kernel only
+// allows this while the process has a single thread
+fn enter_user_namespace() -> io::Result<()> {
+    let (uid, gid) = (getuid(), getgid());
+    if uid.is_root() {
+        return Ok(());
+    }
+    unshare(CloneFlags::CLONE_NEWUSER).map_err(|_| match io::Error::last_os_error() {
+        ref e if e.raw_os_error() == Some(libc::EINVAL) => io::Error::new(e.kind(),
+            "only a single threaded process can enter a user namespace, run as root or with --test-threads=1"),
+        e => e,
+    })?;
+    // gid_map can not be written by an unprivileged process until setgroups is denied
+    fs::write("/proc/self/setgroups", "deny")?;
+    fs::write("/proc/self/uid_map", format!("0 {} 1", uid))?;
+    fs::write("/proc/self/gid_map", format!("0 {} 1", gid))
+}
+
+// wait up to timeout for fd to become readable, TimedOut if it does not, so a test
+// waiting for a frame that got lost fails instead of hanging
+pub fn readable<F: AsRawFd>(fd: &F, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let mut pfd = libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        match unsafe { libc::poll(&mut pfd, 1, left.as_millis() as libc::c_int) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            0 => return Err(io::ErrorKind::TimedOut.into()),
+            _ => return Ok(()),
+        }
+    }
+}
+
+// a private network namespace. the caller's threads never enter it, each run moves a
+// thread of its own in that ends with the call. sockets and devices opened inside keep
+// working from any namespace and the namespace with its veths goes away once they and
+// this handle are dropped
+#[derive(Debug)]
+pub struct Namespace {
+    fd: RawFd,
+}
+
+impl AsRawFd for Namespace {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Namespace {
+    // needs CAP_SYS_ADMIN. an unprivileged process gets it from a user namespace entered
+    // here, which works only while it has a single thread, e.g. cargo test -- --test-threads=1
+    pub fn new() -> io::Result<Namespace> {
+        enter_user_namespace()?;
+        let fd = thread::spawn(|| {
+            unshare(CloneFlags::CLONE_NEWNET).map_err(|_| io::Error::last_os_error())?;
+            open_netns()
+        }).join().map_err(|_| io::Error::other("namespace thread panicked"))??;
+        let ns = Namespace { fd };
+        ns.netlink(|nl| nl.set_up("lo"))?;
+        Ok(ns)
+    }
+
+    // call f on a thread moved into the namespace, a panic in f is resumed here
+    pub fn run<F, T>(&self, f: F) -> io::Result<T>
+        where F: FnOnce() -> T + Send, T: Send
+    {
+        let fd = self.fd;
+        thread::scope(|scope| {
+            let thread = scope.spawn(move || {
+                setns(fd, CloneFlags::CLONE_NEWNET).map_err(|_| io::Error::last_os_error())?;
+                Ok(f())
+            });
+            match thread.join() {
+                Ok(res) => res,
+                Err(cause) => panic::resume_unwind(cause),
+            }
+        })
+    }
+
+    pub fn netlink<F, T>(&self, f: F) -> io::Result<T>
+        where F: FnOnce(&Netlink) -> crate::util::netlink::Result<T> + Send, T: Send
+    {
+        self.run(|| Netlink::new().and_then(|nl| f(&nl)))?.map_err(io::Error::from)
+    }
+
+    // a veth pair with both ends up
+    pub fn add_veth(&self, name: &str, peer: &str) -> io::Result<()> {
+        self.netlink(|nl| {
+            nl.add_veth(name, peer)?;
+            nl.set_up(name)?;
+            nl.set_up(peer)
+        })
+    }
+
+    pub fn del_link(&self, name: &str) -> io::Result<()> {
+        self.netlink(|nl| nl.del_link(name))
+    }
+
+    pub fn set_up(&self, name: &str) -> io::Result<()> {
+        self.netlink(|nl| nl.set_up(name))
+    }
+
+    pub fn add_address(&self, name: &str, addr: IpAddress, prefix: u8) -> io::Result<()> {
+        self.netlink(|nl| nl.add_address(name, addr, prefix))
+    }
+
+    // the interface lives as long as the returned device
+    pub fn tap(&self, name: &str) -> io::Result<TapDevice> {
+        let mut name = name.to_string();
+        self.run(|| TapDevice::new(&mut name))?
+    }
+
+    pub fn raw_socket(&self, name: &str) -> io::Result<RawSocketDevice> {
+        self.run(|| RawSocketDevice::new(name))?
+    }
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+
+    #[test]
+    fn test_isolated() {
+        let ns = Namespace::new().unwrap();
+        ns.add_veth("rveth0", "rveth1").unwrap();
+        let links = ns.netlink(|nl| nl.links()).unwrap();
+        let mut names: Vec<String> = links.into_iter().map(|l| l.name).collect();
+        names.sort();
+        assert_eq!(names, vec!["lo", "rveth0", "rveth1"]);
+        // nothing leaked into the host namespace
+        assert!(Netlink::new().unwrap().index("rveth0").is_err());
+    }
+    #[test]
+    fn test_veth_forward() {
+        let ns = Namespace::new().unwrap();
+        ns.add_veth("rveth0", "rveth1").unwrap();
+        let a = ns.raw_socket("rveth0").unwrap();
+        let b = ns.raw_socket("rveth1").unwrap();
+        let mut frame = [0u8; 60];
+        frame[0..6].copy_from_slice(&[0xff; 6]);
+        frame[12..14].copy_from_slice(&[0x88, 0xb5]);
+        frame[14..18].copy_from_slice(&[0x0e, 0x75, 0x00, 0x01]);
+        let mut buf = [0u8; 1500];
+        let mut tries = 0;
+        a.send(&frame).unwrap();
+        loop {
+            // the veth carrier may still be coming up and drop the first frame
+            if let Err(e) = readable(&b, Duration::from_secs(1)) {
+                assert!(tries < 5, "{}", e);
+                tries += 1;
+                a.send(&frame).unwrap();
+                continue;
+            }
+            let len = b.recv(&mut buf).unwrap();
+            if buf[12..18] == frame[12..18] {
+                assert_eq!(len, 60);
+                break;
+            }
+        }
+        ns.del_link("rveth0").unwrap();
+        assert!(ns.netlink(|nl| nl.index("rveth1")).is_err());
+    }
+    #[test]
+    fn test_tap() {
+        let ns = Namespace::new().unwrap();
+        let dev = ns.tap("rtap0").unwrap();
+        assert_eq!(dev.name(), "rtap0");
+        ns.set_up("rtap0").unwrap();
+        assert!(ns.netlink(|nl| nl.link("rtap0")).unwrap().is_up());
+        drop(dev);
+        assert!(ns.netlink(|nl| nl.index("rtap0")).is_err());
+    }
+    #[test]
+    fn test_parallel() {
+        // the same names in namespaces served by threads running side by side
+        let handles: Vec<_> = (0..4).map(|_| thread::spawn(|| {
+            let ns = Namespace::new().unwrap();
+            ns.add_veth("rpar0", "rpar1").unwrap();
+            ns.netlink(|nl| nl.index("rpar1")).unwrap()
+        })).collect();
+        for h in handles {
+            assert!(h.join().unwrap() > 1);
+        }
+    }
+    #[test]
+    fn test_run_panic() {
+        let ns = Namespace::new().unwrap();
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| ns.run(|| panic!("inside"))));
+        assert!(res.is_err());
+        // the thread survives and the namespace is still usable
+        assert!(ns.netlink(|nl| nl.index("lo")).is_ok());
+    }
+}
@@ -1,28 +1,205 @@
 extern crate proto;
+use std::cell::Cell;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
 use proto::device::Device;
+use proto::device::poll::Reactor;
+use proto::device::qemu_socket::StreamListener;
+use proto::device::stats::Counted;
 use proto::device::tuntap::TapDevice;
-use proto::util;
+use proto::device::udp_tunnel::UdpTunnelDevice;
+use proto::util::netlink::Netlink;
+use proto::packet::ipv4::IpAddress;
 
 fn main() {
-    let (dev0, dev1) = setup();
-    loop {
-        let mut buf0 = [0u8; 256];
-        let len = dev0.recv(&mut buf0).unwrap();
-        println!("[info] ({:?}) recieve {:?} bytes", dev0.name(), len);
-        let len = dev1.send(&mut buf0).unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {
+            let (dev0, dev1) = setup();
+            run(&dev0, &dev1);
+        }
+        // the second port is a udp tunnel to another switch or a qemu vm
+        [flag, local, peer] if flag == "--udp" => {
+            let dev0 = Counted::new(open("dev0", IpAddress::new(192, 168, 100, 20)));
+            let dev1 = Counted::new(UdpTunnelDevice::new(local.as_str(), peer.as_str()).unwrap());
+            run(&dev0, &dev1);
+        }
+        // wait for a vm started with -netdev stream,addr.type=unix,addr.path=PATH
+        [flag, path] if flag == "--qemu" => {
+            let dev0 = Counted::new(open("dev0", IpAddress::new(192, 168, 100, 20)));
+            remove_stale_socket(path).unwrap();
+            let listener = StreamListener::bind_unix(path).unwrap();
+            let dev1 = listener.accept().unwrap();
+            // the stream keeps a partial frame until the rest arrives instead of
+            // stalling the other port
+            dev1.set_nonblocking(true).unwrap();
+            let dev1 = Counted::new(dev1);
+            run(&dev0, &dev1);
+            let _ = fs::remove_file(path);
+        }
+        _ => {
+            eprintln!("usage: ruswitch [--udp LOCAL_ADDR PEER_ADDR | --qemu SOCKET_PATH]");
+            process::exit(2);
+        }
     }
 }
 
-fn setup() -> (TapDevice, TapDevice) {
-    let mut name0 = String::from("dev0");
-    let mut name1 = String::from("dev1");
-    let dev0 = TapDevice::new(&mut name0).unwrap();
-    let dev1 = TapDevice::new(&mut name1).unwrap();
+fn run<A, B>(dev0: &Counted<A>, dev1: &Counted<B>)
+    where A: Device + AsRawFd, B: Device + AsRawFd
+{
+    let closed = [Cell::new(false), Cell::new(false)];
+    let mut reactor = Reactor::new().unwrap();
+    let port0 = reactor.register(dev0, |dev| forward(dev, dev1, &closed[0])).unwrap();
+    let port1 = reactor.register(dev1, |dev| forward(dev, dev0, &closed[1])).unwrap();
+    reactor.every(Duration::from_secs(10), || {
+        report("port0", dev0);
+        report("port1", dev1);
+        Ok(())
+    });
+    // with one of the two ports gone there is nothing left to switch
+    while !closed.iter().any(Cell::get) {
+        reactor.run_once(None).unwrap();
+    }
+    for (name, token, closed) in [("port0", port0, &closed[0]), ("port1", port1, &closed[1])].iter() {
+        if closed.get() {
+            println!("[info] ({}) closed", name);
+            reactor.deregister(*token).unwrap();
+        }
+    }
+}
 
-    util::cmd("ip", vec!["link", "set", "up", "dev", "dev0"]).unwrap();
-    util::cmd("ip", vec!["addr", "add", "192.168.100.20/24", "dev", "dev0"]).unwrap();
-    util::cmd("ip", vec!["link", "set", "up", "dev", "dev1"]).unwrap();
-    util::cmd("ip", vec!["addr", "add", "192.168.100.21/24", "dev", "dev1"]).unwrap();
+// a frame that fails to arrive or to go out is dropped, the switch keeps running.
+// a stream port whose vm went away is marked closed
+fn forward<A: Device, B: Device>(from: &A, to: &B, closed: &Cell<bool>) -> io::Result<()> {
+    let mut buf = [0u8; 1514];
+    let len = match from.recv(&mut buf) {
+        Ok(len) => len,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            closed.set(true);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("[warn] recv failed: {}", e);
+            return Ok(());
+        }
+    };
+    if len == 0 {
+        return Ok(());
+    }
+    if let Err(e) = to.send(&buf[..len]) {
+        eprintln!("[warn] send failed: {}", e);
+    }
+    Ok(())
+}
+
+fn report<D: Device>(port: &str, dev: &Counted<D>) {
+    let stats = dev.reset();
+    println!("[info] ({}) rx {} packets {} bytes {} errors, tx {} packets {} bytes {} errors",
+             port,
+             stats.rx.packets, stats.rx.bytes, stats.rx.errors,
+             stats.tx.packets, stats.tx.bytes, stats.tx.errors);
+}
+
+// a socket left behind by a switch that was killed would make the bind fail
+fn remove_stale_socket<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    match fs::symlink_metadata(&path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(&path),
+        Ok(_) => Err(io::ErrorKind::AlreadyExists.into()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
 
-    (dev0, dev1)
+fn setup() -> (Counted<TapDevice>, Counted<TapDevice>) {
+    let dev0 = open("dev0", IpAddress::new(192, 168, 100, 20));
+    let dev1 = open("dev1", IpAddress::new(192, 168, 100, 21));
+    (Counted::new(dev0), Counted::new(dev1))
+}
+
+// interfaces created beforehand are used as they are, so ruswitch can run unprivileged
+fn open(name: &str, addr: IpAddress) -> TapDevice {
+    match TapDevice::open_existing(name) {
+        Ok(dev) => dev,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let dev = TapDevice::new(&mut name.to_string()).unwrap();
+            let nl = Netlink::new().unwrap();
+            nl.set_up(name).unwrap();
+            nl.add_address(name, addr, 24).unwrap();
+            dev
+        }
+        Err(e) => panic!("failed to open {}: {}", name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use proto::device::qemu_socket::StreamDevice;
+    use proto::util::netns::Namespace;
+
+    #[test]
+    fn test_setup() {
+        let ns = Namespace::new().unwrap();
+        let (dev0, dev1) = ns.run(setup).unwrap();
+        assert_eq!((dev0.get_ref().name(), dev1.get_ref().name()), ("dev0".to_string(), "dev1".to_string()));
+        assert!(ns.netlink(|nl| nl.link("dev0")).unwrap().is_up());
+        assert!(ns.netlink(|nl| nl.link("dev1")).unwrap().is_up());
+        // nothing was created in the host namespace
+        assert!(Netlink::new().unwrap().index("dev0").is_err());
+    }
+    #[test]
+    fn test_port_closed() {
+        let (a, vm0) = UnixStream::pair().unwrap();
+        let (b, vm1) = UnixStream::pair().unwrap();
+        let dev0 = Counted::new(StreamDevice::from_unix(a));
+        let dev1 = Counted::new(StreamDevice::from_unix(b));
+        (&vm0).write_all(&[0, 0, 0, 2, 0xab, 0xcd]).unwrap();
+        drop(vm0);
+        // forwards the frame, then stops on the closed port instead of panicking
+        run(&dev0, &dev1);
+        let mut raw = [0u8; 6];
+        (&vm1).read_exact(&mut raw).unwrap();
+        assert_eq!(raw, [0, 0, 0, 2, 0xab, 0xcd]);
+        assert_eq!(dev0.stats().rx.packets, 1);
+    }
+    #[test]
+    fn test_partial_frame() {
+        let (a, vm0) = UnixStream::pair().unwrap();
+        let (b, vm1) = UnixStream::pair().unwrap();
+        let dev0 = Counted::new(StreamDevice::from_unix(a));
+        let dev1 = Counted::new(StreamDevice::from_unix(b));
+        dev0.get_ref().set_nonblocking(true).unwrap();
+        dev1.get_ref().set_nonblocking(true).unwrap();
+        // half a frame on one port does not hold up the other
+        (&vm0).write_all(&[0, 0, 0, 2, 0xab]).unwrap();
+        (&vm1).write_all(&[0, 0, 0, 1, 0x11]).unwrap();
+        drop(vm1);
+        run(&dev0, &dev1);
+        let mut raw = [0u8; 5];
+        (&vm0).read_exact(&mut raw).unwrap();
+        assert_eq!(raw, [0, 0, 0, 1, 0x11]);
+        assert_eq!(dev0.stats().rx.packets, 0);
+    }
+    #[test]
+    fn test_remove_stale_socket() {
+        let path = env::temp_dir().join(format!("ruswitch-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        remove_stale_socket(&path).unwrap();
+        drop(UnixListener::bind(&path).unwrap());
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+        // anything but a socket is left alone
+        fs::write(&path, b"").unwrap();
+        assert_eq!(remove_stale_socket(&path).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(&path).unwrap();
+    }
 }